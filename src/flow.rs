@@ -0,0 +1,131 @@
+//! Flow sensor pulses turned into water volume, and the water each zone run got so far.
+
+/// Calibration of a pulse output flow sensor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlowMeter {
+    pub pulses_per_liter: f64,
+}
+
+impl FlowMeter {
+    /// Common YF-S201 style hall sensors give about 450 pulses per liter
+    pub const DEFAULT: FlowMeter = FlowMeter {
+        pulses_per_liter: 450.0,
+    };
+
+    pub fn is_valid(&self) -> bool {
+        self.pulses_per_liter.is_finite() && self.pulses_per_liter > 0.0
+    }
+
+    pub fn milliliters(&self, pulses: u64) -> u64 {
+        (pulses as f64 * 1000.0 / self.pulses_per_liter).round() as u64
+    }
+}
+
+/// Pulses a free running counter saw between two reads, across its wrap-around
+pub fn pulses_since(count: u32, last: u32) -> u32 {
+    count.wrapping_sub(last)
+}
+
+/// Share of the pulses counted on the main line for each of `open_zones` open zones. The
+/// sensor can't tell them apart, so it is only exact with one zone open at a time.
+pub fn share_pulses(pulses: u32, open_zones: usize) -> u64 {
+    u64::from(pulses) / open_zones.max(1) as u64
+}
+
+/// Water delivered to one zone in its current run, in sensor pulses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Delivery {
+    /// Start of the run being counted (local seconds)
+    run_start: i64,
+    pulses: u64,
+}
+
+impl Delivery {
+    /// Count `pulses` towards the run starting at `run_start`, a new run starts from zero
+    pub fn count(&mut self, run_start: i64, pulses: u64) {
+        if self.run_start != run_start {
+            *self = Delivery {
+                run_start,
+                pulses: 0,
+            };
+        }
+        self.pulses += pulses;
+    }
+
+    /// Water the run starting at `run_start` got so far, 0 if it isn't the one being counted
+    pub fn milliliters(&self, run_start: i64, meter: &FlowMeter) -> u64 {
+        if self.run_start == run_start {
+            meter.milliliters(self.pulses)
+        } else {
+            0
+        }
+    }
+
+    /// Whether the run starting at `run_start` already got its `target_ml`
+    pub fn is_complete(&self, run_start: i64, target_ml: u32, meter: &FlowMeter) -> bool {
+        self.milliliters(run_start, meter) >= u64::from(target_ml)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUN_START: i64 = 1_704_085_200;
+
+    #[test]
+    fn closes_once_the_target_volume_is_delivered() {
+        let meter = FlowMeter::DEFAULT;
+        let mut delivery = Delivery::default();
+
+        // 10 L/min is 75 pulses a second, 10 L take a minute
+        let mut ticks = 0;
+        while !delivery.is_complete(RUN_START, 10_000, &meter) {
+            delivery.count(RUN_START, share_pulses(75, 1));
+            ticks += 1;
+        }
+        assert_eq!(ticks, 60);
+        assert_eq!(delivery.milliliters(RUN_START, &meter), 10_000);
+    }
+
+    #[test]
+    fn a_new_run_counts_from_zero() {
+        let meter = FlowMeter {
+            pulses_per_liter: 1.0,
+        };
+        let mut delivery = Delivery::default();
+        delivery.count(RUN_START, 40);
+        assert!(delivery.is_complete(RUN_START, 40_000, &meter));
+
+        // The next cycle or day
+        assert!(!delivery.is_complete(RUN_START + 3600, 40_000, &meter));
+        delivery.count(RUN_START + 3600, 5);
+        assert_eq!(delivery.milliliters(RUN_START + 3600, &meter), 5_000);
+        assert_eq!(delivery.milliliters(RUN_START, &meter), 0);
+    }
+
+    #[test]
+    fn pulses_are_shared_between_open_zones() {
+        assert_eq!(share_pulses(90, 2), 45);
+        assert_eq!(share_pulses(90, 0), 90);
+    }
+
+    #[test]
+    fn counter_wraps_around() {
+        assert_eq!(pulses_since(5, u32::MAX - 4), 10);
+        assert_eq!(pulses_since(1_000, 400), 600);
+    }
+
+    #[test]
+    fn calibration() {
+        let meter = FlowMeter {
+            pulses_per_liter: 7.5,
+        };
+        assert_eq!(meter.milliliters(15), 2_000);
+        assert!(meter.is_valid());
+        assert!(!FlowMeter {
+            pulses_per_liter: 0.0
+        }
+        .is_valid());
+    }
+}
@@ -62,6 +62,16 @@ function formatDuration(seconds) {
   return `${minutes}m`;
 }
 
+// Weekday mask bits: Monday = bit 0 ... Sunday = bit 6
+const WEEKDAYS = ["Mo", "Tu", "We", "Th", "Fr", "Sa", "Su"];
+
+// Helper: Format weekday mask as "Mo We Fr" or "Every day"
+function formatWeekdays(mask) {
+  if (mask === 0x7f) return "Every day";
+  if (mask === 0) return "Never";
+  return WEEKDAYS.filter((_, i) => mask & (1 << i)).join(" ");
+}
+
 // Helper: Convert HH:MM to seconds from midnight
 function timeToSeconds(timeStr) {
   const [hours, minutes] = timeStr.split(":").map(Number);
@@ -82,29 +92,147 @@ async function loadInfo() {
     const container = document.getElementById("aspersores");
     container.innerHTML = data.aspersores
       .map((a) => {
-        const startTime = formatTime(a.init_time);
-        const endTime = formatTime(a.init_time + a.duration);
+        const schedules = a.slots
+          .map((s) =>
+            s.start
+              ? `<div>🌅 ${formatSolarStart(s.start)} (${formatDuration(
+                  s.duration
+                )})</div>`
+              : `<div>🕐 ${formatTime(s.init_time)} → ${formatTime(
+                  (s.init_time + s.duration) % (24 * 3600)
+                )} (${formatDuration(s.duration)})</div>`
+          )
+          .join("");
 
         return `
         <div class="aspersor ${a.on ? "on" : "off"}">
           <div class="aspersor-header">
             <span class="name">${a.name}</span>
-            <span class="status">${a.on ? "🟢 ON" : "⚫ OFF"}</span>
+            <span class="status">${a.on ? "🟢 ON" : "⚫ OFF"}${
+          a.enabled ? "" : " ⛔ Disabled"
+        }</span>
           </div>
-          <div class="schedule">
-            🕐 ${startTime} → ${endTime} (${formatDuration(a.duration)})
+          <div class="edit-row">
+            <label><input type="checkbox" ${
+              a.enabled ? "checked" : ""
+            } onchange="setEnabled('${a.name}', this.checked)"> Enabled</label>
+          </div>
+          <div class="schedule">${schedules}<div>📅 ${
+          a.interval
+            ? `Every ${a.interval.every_days} days from ${a.interval.start}`
+            : formatWeekdays(a.weekdays)
+        }</div><div>⏭ Next: ${a.next_run_day ?? "never"}</div>${
+          a.cycle_soak
+            ? `<div>💧 Cycles of ${formatDuration(
+                a.cycle_soak.max_cycle
+              )}, soak ${formatDuration(a.cycle_soak.soak)}</div>`
+            : ""
+        }${
+          a.volume !== null
+            ? `<div>🪣 ${a.volume} L per run${
+                a.delivered !== null ? `, ${a.delivered.toFixed(1)} L so far` : ""
+              }</div>`
+            : ""
+        }${
+          a.remaining > 0
+            ? `<div>⏳ Remaining today: ${formatDuration(a.remaining)}</div>`
+            : ""
+        }${
+          a.override
+            ? `<div>✋ Held ${a.override.on ? "ON" : "OFF"} ${
+                a.override.until ? "until " + a.override.until : "until cleared"
+              }</div>`
+            : ""
+        }${
+          a.manual_run_remaining !== null
+            ? `<div>▶️ Manual run: ${formatDuration(
+                a.manual_run_remaining
+              )} left</div>`
+            : ""
+        }<div>⏱ Ran today ${formatDuration(a.runtime.today)} · week ${formatDuration(
+          a.runtime.week
+        )} · month ${formatDuration(a.runtime.month)}</div>${
+          a.tripped
+            ? `<div>🛑 Closed by the safety limit of ${formatDuration(
+                a.max_on
+              )}, until its run ends</div>`
+            : ""
+        }${
+          a.faults.length > 0
+            ? `<div>⚠️ Safety cutoffs: ${a.faults.join(", ")}</div>`
+            : ""
+        }</div>
+          <div class="edit-row" id="weekdays-${a.name}">
+            ${WEEKDAYS.map(
+              (day, i) =>
+                `<label><input type="checkbox" value="${i}" ${
+                  a.weekdays & (1 << i) ? "checked" : ""
+                }>${day}</label>`
+            ).join("")}
           </div>
           <div class="edit-row">
-            <label>Start: <input type="time" id="start-${
+            <label>Every <input type="number" min="0" max="365" id="interval-days-${
               a.name
-            }" value="${startTime}"></label>
-            <label>End: <input type="time" id="end-${
+            }" value="${a.interval ? a.interval.every_days : 0}"> days</label>
+            <label>from <input type="date" id="interval-start-${a.name}" value="${
+          a.interval ? a.interval.start : ""
+        }"></label>
+          </div>
+          <div class="edit-row">
+            <label>Cycle <input type="number" min="0" id="max-cycle-${
+              a.name
+            }" value="${a.cycle_soak ? a.cycle_soak.max_cycle / 60 : 0}"> min</label>
+            <label>soak <input type="number" min="0" id="soak-${a.name}" value="${
+          a.cycle_soak ? a.cycle_soak.soak / 60 : 0
+        }"> min</label>
+          </div>
+          <div class="edit-row">
+            <label>Water <input type="number" min="0" max="100000" id="volume-${
               a.name
-            }" value="${endTime}"></label>
+            }" value="${a.volume ?? 0}"> L per run (0 = by time, needs a flow sensor)</label>
+          </div>
+          <div class="edit-row">
+            <label>Never on longer than <input type="number" min="1" max="1440" id="max-on-${
+              a.name
+            }" value="${a.max_on / 60}"> min</label>
+          </div>
+          <div id="slots-${a.name}">
+            ${a.slots.map((s) => slotRow(s)).join("")}
+          </div>
+          <div class="edit-row">
+            <button class="save-btn" onclick="addSlot('${
+              a.name
+            }')">➕ Slot</button>
             <button class="save-btn" onclick="updateAspersor('${
               a.name
             }')">💾 Save</button>
           </div>
+          <div class="edit-row">
+            <label>Run for <input type="number" min="1" max="720" id="run-minutes-${
+              a.name
+            }" value="10"> min</label>
+            <button class="save-btn" onclick="runAspersor('${a.name}')">▶️ Run</button>
+            ${
+              a.manual_run_remaining !== null
+                ? `<button class="save-btn" onclick="runAspersor('${a.name}', 0)">⏹ Stop</button>`
+                : ""
+            }
+          </div>
+          <div class="edit-row">
+            <label>Override <select id="override-mode-${a.name}">
+              <option value="auto">Follow schedule</option>
+              <option value="on" ${
+                a.override && a.override.on ? "selected" : ""
+              }>Force on</option>
+              <option value="off" ${
+                a.override && !a.override.on ? "selected" : ""
+              }>Force off</option>
+            </select></label>
+            <label>for <input type="number" min="0" id="override-minutes-${
+              a.name
+            }" value="0"> min (0 = until cleared)</label>
+            <button class="save-btn" onclick="setOverride('${a.name}')">✋ Set</button>
+          </div>
           <button onclick="toggleAspersor('${a.name}')">${
           a.on ? "Turn Off" : "Turn On"
         }</button>
@@ -113,34 +241,448 @@ async function loadInfo() {
       })
       .join("");
 
+    renderProgram(data.program);
+    renderController(data);
+    loadUpcoming();
+
     const modeBtn = document.getElementById("manual-mode");
     modeBtn.className = data.manual_mode ? "mode-btn manual" : "mode-btn auto";
     modeBtn.innerHTML = data.manual_mode
-      ? "🔧 Manual Mode <small>(click for Auto)</small>"
+      ? `🔧 Manual Mode <small>(click for Auto${
+          data.manual_mode_until ? ", auto again at " + data.manual_mode_until : ""
+        })</small>`
       : "⏰ Auto Mode <small>(click for Manual)</small>";
   } catch (err) {
     console.error("Failed to load info:", err);
   }
 }
 
+// Sequential program: zones run one after another from a single start time
+function renderProgram(program) {
+  const windows = program.windows
+    .map(
+      (w) =>
+        `<div>${w.name}: ${formatTime(w.init_time)} → ${formatTime(
+          (w.init_time + w.duration) % (24 * 3600)
+        )} (${formatDuration(w.duration)})</div>`
+    )
+    .join("");
+
+  document.getElementById("program").innerHTML = `
+    <div class="aspersor-header">
+      <span class="name">Sequential program</span>
+      <span class="status">${program.enabled ? "🟢 ON" : "⚫ OFF"}</span>
+    </div>
+    <div class="schedule">${
+      program.enabled ? windows || "No zones today" : "Zones follow their own slots"
+    }</div>
+    <div class="edit-row">
+      <label><input type="checkbox" id="program-enabled" ${
+        program.enabled ? "checked" : ""
+      }> Enabled</label>
+      <label>Start: <input type="time" id="program-start" value="${formatTime(
+        program.start_time
+      )}"></label>
+    </div>
+    <div class="edit-row">
+      <label>Order: <input type="text" id="program-order" value="${program.order.join(
+        ","
+      )}"></label>
+      <button class="save-btn" onclick="updateProgram()">💾 Save</button>
+    </div>
+  `;
+}
+
+// Next planned runs, straight from the schedules
+async function loadUpcoming() {
+  try {
+    const response = await fetch("/upcoming?count=10");
+    const data = await response.json();
+
+    document.getElementById("upcoming").innerHTML = `
+    <div class="schedule">${
+      data.runs
+        .map(
+          (r) =>
+            `<div>${r.restricted ? "🚫" : "🕐"} ${r.start} → ${r.end.slice(-5)} ${
+              r.name
+            } (${r.reason.replace("_", " ")}${
+              r.restricted ? ", restricted: " + r.restricted : ""
+            })</div>`
+        )
+        .join("") || "Nothing planned"
+    }</div>
+  `;
+  } catch (err) {
+    console.error("Failed to load upcoming runs:", err);
+  }
+}
+
+// Controller-wide settings, and zones waiting for a free slot
+function renderController(data) {
+  const waiting = data.queued
+    .map(
+      (q) =>
+        `<div>⏳ ${q.name}: wanted ${formatTime(q.requested_start)}, starts ${formatTime(
+          q.start
+        )}</div>`
+    )
+    .join("");
+
+  document.getElementById("queue").innerHTML = `
+    <div class="schedule">${
+      data.rain_delay_until
+        ? `<div>🌧 Rain delay until ${data.rain_delay_until}</div>`
+        : ""
+    }${
+      data.restricted_now
+        ? `<div>🚫 Restricted now (${data.restricted_now})</div>`
+        : ""
+    }${waiting || "No zones waiting"}${data.skipped
+      .map((s) => `<div>⏭ Skipped ${s.name} at ${s.start}: ${s.reason}</div>`)
+      .join("")}${data.catch_up
+      .map((c) => `<div>🔁 Catching up ${c.name} at ${c.start}</div>`)
+      .join("")}</div>
+    <div class="edit-row">
+      <label>Restrictions: <input type="text" id="restrictions" placeholder="odd,no:36000-68400@1201-0229" value="${
+        data.restrictions
+      }"></label>
+      <button class="save-btn" onclick="updateRestrictions()">💾 Save</button>
+    </div>
+    <div>📆 ${data.season ? `Season: ${data.season}` : "No season, zones use their own slots"}</div>
+    ${data.seasons
+      .map(
+        (season, i) => `
+    <div class="edit-row">
+      <input type="text" id="season-${i}" value="${season}">
+      <button class="save-btn" onclick="setSeason('season-${i}')">💾 Save</button>
+      <button class="save-btn" onclick="deleteSeason('${season.split("@")[0]}')">✖ Delete</button>
+    </div>`
+      )
+      .join("")}
+    <div class="edit-row">
+      <label>New season: <input type="text" id="season-new" placeholder="summer@1101-0331;program=75600;zone=75600:3600"></label>
+      <button class="save-btn" onclick="setSeason('season-new')">➕ Add</button>
+    </div>
+    <div class="edit-row">
+      <label>Rain delay: <input type="number" min="0" max="365" id="rain-days" value="1"> days</label>
+      <button class="save-btn" onclick="setRainDelay()">🌧 Delay</button>
+      ${
+        data.rain_delay_until
+          ? `<button class="save-btn" onclick="setRainDelay(0)">✖ Cancel</button>`
+          : ""
+      }
+    </div>
+    <div>🔁 Booted after: ${data.reset.reason}${
+      data.reset.fault_resets > 0
+        ? ` · ⚠️ ${data.reset.fault_resets} watchdog/panic resets, last: ${data.reset.last_fault}`
+        : ""
+    }</div>
+    ${
+      data.master
+        ? `<div>🚰 Master valve ${data.master.on ? "🟢 ON" : "⚫ OFF"}</div>
+    <div class="edit-row">
+      <label>Master lead <input type="number" min="0" max="60" id="master-lead" value="${data.master.lead}"> s</label>
+      <label>lag <input type="number" min="0" max="600" id="master-lag" value="${data.master.lag}"> s</label>
+    </div>`
+        : ""
+    }
+    ${
+      data.flow
+        ? `<div>🌊 Flow sensor: ${data.flow.pulses} pulses counted</div>
+    <div class="edit-row">
+      <label>Calibration <input type="number" min="0.001" step="0.001" id="pulses-per-liter" value="${data.flow.pulses_per_liter}"> pulses/L</label>
+    </div>`
+        : ""
+    }
+    <div class="edit-row">
+      <label>Max open at once (0 = no limit): <input type="number" min="0" max="255" id="max-concurrent" value="${data.max_concurrent}"></label>
+      <label>Gap between zones <input type="number" min="0" max="600" id="zone-gap" value="${data.zone_gap}"> s</label>
+    </div>
+    <div>${
+      data.sun
+        ? `🌅 ${formatTime(data.sun.sunrise)} · 🌇 ${formatTime(data.sun.sunset)}`
+        : "No sunrise/sunset today"
+    }</div>
+    <div class="edit-row">
+      <label>Lat: <input type="number" step="0.0001" id="latitude" value="${
+        data.location.latitude
+      }"></label>
+      <label>Lon: <input type="number" step="0.0001" id="longitude" value="${
+        data.location.longitude
+      }"></label>
+    </div>
+    <div class="edit-row">
+      <label>Manual mode back to auto after <input type="number" min="0" id="manual-timeout" value="${
+        data.manual_timeout
+      }"> min (0 = never)</label>
+    </div>
+    <div class="edit-row">
+      <label>Catch up runs missed in a power cut within <input type="number" min="0" max="1440" id="catch-up-grace" value="${
+        data.catch_up_grace / 60
+      }"> min (0 = off)</label>
+    </div>
+    <div class="edit-row">
+      <label>Water budget: <input type="number" min="1" max="200" id="budget-percent" value="${data.budget_percent}"> %</label>
+      <button class="save-btn" onclick="updateController()">💾 Save</button>
+    </div>
+  `;
+}
+
+// Rules: odd | even | no:start-end (seconds from midnight), each optionally @MMDD-MMDD
+async function updateRestrictions() {
+  const rules = document
+    .getElementById("restrictions")
+    .value.split(",")
+    .map((rule) => rule.trim())
+    .filter((rule) => rule)
+    .join(",");
+
+  try {
+    const response = await fetch(`/set_restrictions?rules=${rules}`);
+    const data = await response.json();
+
+    if (!data.ok) {
+      alert("Failed to update restrictions: " + data.error);
+    }
+    loadInfo();
+  } catch (err) {
+    console.error("Failed to update restrictions:", err);
+  }
+}
+
+// Season: name@MMDD-MMDD, then ;program=start and ;zone=slots (as in the slots field) as needed
+async function setSeason(inputId) {
+  const value = document.getElementById(inputId).value.trim();
+
+  try {
+    const response = await fetch(`/set_season?value=${value}`);
+    const data = await response.json();
+
+    if (!data.ok) {
+      alert("Failed to save season: " + data.error);
+    }
+    loadInfo();
+  } catch (err) {
+    console.error("Failed to save season:", err);
+  }
+}
+
+async function deleteSeason(name) {
+  try {
+    await fetch(`/set_season?delete=${name}`);
+    loadInfo();
+  } catch (err) {
+    console.error("Failed to delete season:", err);
+  }
+}
+
+async function setRainDelay(days) {
+  if (days === undefined) {
+    days = document.getElementById("rain-days").value || 0;
+  }
+
+  try {
+    const response = await fetch(`/rain_delay?days=${days}`);
+    const data = await response.json();
+
+    if (!data.ok) {
+      alert("Failed to set rain delay: " + data.error);
+    }
+    loadInfo();
+  } catch (err) {
+    console.error("Failed to set rain delay:", err);
+  }
+}
+
+async function updateController() {
+  const maxConcurrent = document.getElementById("max-concurrent").value || 0;
+  const zoneGap = document.getElementById("zone-gap").value || 0;
+  const budgetPercent = document.getElementById("budget-percent").value || 100;
+  const manualTimeout = document.getElementById("manual-timeout").value || 0;
+  const catchUpGrace =
+    (document.getElementById("catch-up-grace").value || 0) * 60;
+  const latitude = document.getElementById("latitude").value;
+  const longitude = document.getElementById("longitude").value;
+
+  // Only controllers with a master valve show (and accept) its timings
+  const masterLead = document.getElementById("master-lead");
+  const masterLag = document.getElementById("master-lag");
+  const master = masterLead
+    ? `&master_lead=${masterLead.value || 0}&master_lag=${masterLag.value || 0}`
+    : "";
+  // Same for the flow sensor calibration
+  const pulsesPerLiter = document.getElementById("pulses-per-liter");
+  const flow = pulsesPerLiter
+    ? `&pulses_per_liter=${pulsesPerLiter.value}`
+    : "";
+
+  try {
+    const response = await fetch(
+      `/set_controller?max_concurrent=${maxConcurrent}&zone_gap=${zoneGap}` +
+        `&budget_percent=${budgetPercent}` +
+        `&latitude=${latitude}&longitude=${longitude}&manual_timeout=${manualTimeout}` +
+        `&catch_up_grace=${catchUpGrace}${master}${flow}`
+    );
+    const data = await response.json();
+
+    if (!data.ok) {
+      alert("Failed to update controller: " + data.error);
+    }
+    loadInfo();
+  } catch (err) {
+    console.error("Failed to update controller:", err);
+  }
+}
+
+async function updateProgram() {
+  const enabled = document.getElementById("program-enabled").checked ? 1 : 0;
+  const startTime = timeToSeconds(
+    document.getElementById("program-start").value
+  );
+  const order = document
+    .getElementById("program-order")
+    .value.split(",")
+    .map((name) => name.trim())
+    .filter((name) => name)
+    .join(",");
+
+  try {
+    const response = await fetch(
+      `/set_program?enabled=${enabled}&start_time=${startTime}&order=${order}`
+    );
+    const data = await response.json();
+
+    if (!data.ok) {
+      alert("Failed to update program: " + data.error);
+    }
+    loadInfo();
+  } catch (err) {
+    console.error("Failed to update program:", err);
+  }
+}
+
 // Add toggle function for manual mode
 async function toggleManualMode() {
   await fetch("/toggle/manual_mode");
   loadInfo(); // Refresh to show new state
 }
 
+// Disabled zones keep their schedule but the controller skips them
+async function setEnabled(name, enabled) {
+  await fetch(`/update_aspersor/${name}?enabled=${enabled ? 1 : 0}`);
+  loadInfo(); // Refresh
+}
+
+// Per-zone override: force on/off, optionally for a number of minutes
+async function setOverride(name) {
+  const mode = document.getElementById(`override-mode-${name}`).value;
+  const minutes = Number(
+    document.getElementById(`override-minutes-${name}`).value || 0
+  );
+  const expiry = mode !== "auto" && minutes > 0 ? `&minutes=${minutes}` : "";
+
+  try {
+    const response = await fetch(`/override?name=${name}&mode=${mode}${expiry}`);
+    const data = await response.json();
+
+    if (!data.ok) {
+      alert("Failed to set override: " + data.error);
+    }
+    loadInfo();
+  } catch (err) {
+    console.error("Failed to set override:", err);
+  }
+}
+
+// Timed manual run, turned off by the controller when the time is up
+async function runAspersor(name, minutes) {
+  if (minutes === undefined) {
+    minutes = document.getElementById(`run-minutes-${name}`).value || 0;
+  }
+  await fetch(`/run?name=${name}&minutes=${minutes}`);
+  loadInfo(); // Refresh
+}
+
 async function toggleAspersor(name) {
   await fetch("/toggle/" + name);
   loadInfo(); // Refresh
 }
 
-// Update aspersor schedule
-async function updateAspersor(name) {
-  const startInput = document.getElementById(`start-${name}`);
-  const endInput = document.getElementById(`end-${name}`);
+// Helper: Split a solar start like "sr-1800" into kind ("sr"/"ss") and offset seconds
+function parseSolarStart(start) {
+  return { kind: start.slice(0, 2), offset: Number(start.slice(2) || 0) };
+}
+
+// Helper: Format "sr-1800" as "30m before sunrise"
+function formatSolarStart(start) {
+  const { kind, offset } = parseSolarStart(start);
+  const sun = kind === "sr" ? "sunrise" : "sunset";
+  if (offset === 0) return `At ${sun}`;
+  return `${formatDuration(Math.abs(offset))} ${offset < 0 ? "before" : "after"} ${sun}`;
+}
+
+// Editable row for one slot: start/end for clock times, offset/duration for sunrise/sunset
+function slotRow(slot) {
+  const solar = slot.start ? parseSolarStart(slot.start) : null;
+  const anchor = solar ? solar.kind : "clock";
+  const option = (value, label) =>
+    `<option value="${value}" ${anchor === value ? "selected" : ""}>${label}</option>`;
+
+  const times = solar
+    ? `<label>Offset: <input type="number" class="slot-offset" value="${
+        solar.offset / 60
+      }"> min</label>
+      <label>For: <input type="number" min="1" class="slot-minutes" value="${
+        slot.duration / 60
+      }"> min</label>`
+    : `<label>Start: <input type="time" class="slot-start" value="${formatTime(
+        slot.init_time
+      )}"></label>
+      <label>End: <input type="time" class="slot-end" value="${formatTime(
+        (slot.init_time + slot.duration) % (24 * 3600)
+      )}"></label>`;
+
+  return `
+    <div class="edit-row slot-row">
+      <select class="slot-anchor" onchange="changeAnchor(this)">
+        ${option("clock", "🕐")}${option("sr", "🌅 Sunrise")}${option("ss", "🌇 Sunset")}
+      </select>
+      ${times}
+      <button class="save-btn" onclick="this.parentElement.remove()">✖</button>
+    </div>
+  `;
+}
+
+// Swap a row between clock and sunrise/sunset inputs, keeping the duration
+function changeAnchor(select) {
+  const row = select.parentElement;
+  const slot = readSlot(row) ?? { init_time: 6 * 3600, duration: 30 * 60 };
+  slot.start = select.value === "clock" ? null : select.value;
+  if (slot.start && !row.querySelector(".slot-offset")) slot.start += "+0";
+  if (!slot.start && !row.querySelector(".slot-start")) slot.init_time = 6 * 3600;
+  row.outerHTML = slotRow(slot);
+}
+
+// Slot in a row as { init_time, duration, start }, null if invalid
+function readSlot(row) {
+  const offset = row.querySelector(".slot-offset");
+  if (offset) {
+    const kind = row.querySelector(".slot-anchor").value;
+    const seconds = minutesToSeconds(Number(offset.value || 0));
+    const duration = minutesToSeconds(
+      Number(row.querySelector(".slot-minutes").value || 0)
+    );
+    if (isNaN(seconds) || duration <= 0 || duration > 24 * 3600) return null;
+    return {
+      init_time: 0,
+      duration,
+      start: `${kind}${seconds < 0 ? "" : "+"}${seconds}`,
+    };
+  }
 
-  const initTime = timeToSeconds(startInput.value);
-  const endTime = timeToSeconds(endInput.value);
+  const initTime = timeToSeconds(row.querySelector(".slot-start").value);
+  const endTime = timeToSeconds(row.querySelector(".slot-end").value);
 
   // Calculate duration (handle midnight crossing)
   let duration;
@@ -151,15 +693,81 @@ async function updateAspersor(name) {
     duration = 24 * 3600 - initTime + endTime;
   }
 
-  // Validate
-  if (duration <= 0 || duration > 24 * 3600) {
-    alert("Invalid time range");
+  if (isNaN(duration) || duration <= 0 || duration > 24 * 3600) return null;
+  return { init_time: initTime, duration, start: null };
+}
+
+function addSlot(name) {
+  const slots = document.getElementById(`slots-${name}`);
+  if (slots.querySelectorAll(".slot-row").length >= 4) {
+    alert("Max 4 slots per zone");
     return;
   }
+  slots.insertAdjacentHTML(
+    "beforeend",
+    slotRow({ init_time: 6 * 3600, duration: 30 * 60, start: null })
+  );
+}
+
+// Update aspersor schedule
+async function updateAspersor(name) {
+  const rows = document.querySelectorAll(`#slots-${name} .slot-row`);
+  const slots = [];
+
+  for (const row of rows) {
+    const slot = readSlot(row);
+
+    // Validate
+    if (!slot) {
+      alert("Invalid time range");
+      return;
+    }
+    slots.push(`${slot.start ?? slot.init_time}:${slot.duration}`);
+  }
+
+  if (slots.length === 0) {
+    alert("At least one slot is required");
+    return;
+  }
+
+  let weekdays = 0;
+  document
+    .querySelectorAll(`#weekdays-${name} input:checked`)
+    .forEach((input) => (weekdays |= 1 << Number(input.value)));
+
+  // 0 days uses the weekdays above, otherwise every N days from the start date
+  const intervalDays = Number(
+    document.getElementById(`interval-days-${name}`).value || 0
+  );
+  const intervalStart = document.getElementById(`interval-start-${name}`).value;
+  let interval = `&interval_days=${intervalDays}`;
+  if (intervalDays > 0) {
+    if (!intervalStart) {
+      alert("Pick a start date for the interval");
+      return;
+    }
+    interval += `&interval_start=${intervalStart}`;
+  }
+
+  // Cycle of 0 minutes runs each slot in one go
+  const maxCycle = minutesToSeconds(
+    Number(document.getElementById(`max-cycle-${name}`).value || 0)
+  );
+  const soak = minutesToSeconds(
+    Number(document.getElementById(`soak-${name}`).value || 0)
+  );
+  // 0 liters waters for the slot durations, otherwise they are only the cap
+  const volume = document.getElementById(`volume-${name}`).value || 0;
+  // Safety cutoff, whatever opened the valve
+  const maxOn = minutesToSeconds(
+    Number(document.getElementById(`max-on-${name}`).value || 0)
+  );
 
   try {
     const response = await fetch(
-      `/update_aspersor/${name}?init_time=${initTime}&duration=${duration}`
+      `/update_aspersor/${name}?slots=${slots.join(
+        ","
+      )}&weekdays=${weekdays}${interval}&max_cycle=${maxCycle}&soak=${soak}&volume=${volume}&max_on=${maxOn}`
     );
     const data = await response.json();
 
@@ -2,18 +2,27 @@ use core::str;
 use std::{
     net::Ipv4Addr,
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{Ok, Result};
-use chrono::{TimeDelta, Timelike, Utc};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
         delay::Delay,
-        gpio::{Gpio25, Gpio26, Gpio32, Gpio33, InputOutput, InputPin, OutputPin, Pin, PinDriver},
+        gpio::{
+            AnyIOPin, AnyInputPin, Gpio25, Gpio26, Gpio32, Gpio33, IOPin, Input, InputOutput,
+            InterruptType, Pin, PinDriver, Pull,
+        },
         peripheral::{self},
         prelude::Peripherals,
+        reset::ResetReason,
+        task::watchdog::{TWDTConfig, TWDTDriver},
     },
     http::{
         server::{Configuration, EspHttpServer},
@@ -29,14 +38,32 @@ use esp_idf_svc::{
     },
 };
 use log::info;
+use schedule::flow::{Delivery, FlowMeter};
+use schedule::runtime::RuntimeLog;
+use schedule::{
+    Anchor, ClockTrust, ControllerSchedule, CycleSoak, Interval, Location, ManualRun, MasterTiming,
+    Override, Program, SafetyCutoff, Season, Slot, ZoneSchedule,
+};
 use serde_json::{json, Value};
 
 mod root_html;
+mod schedule;
+
+/// How long the main loop may go without a tick before the task watchdog closes the valves
+/// and resets the chip
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
 
+    // Close every valve on a panic, before the default hook prints it and the chip resets
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        fail_safe();
+        default_hook(info);
+    }));
+
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
 
@@ -44,12 +71,14 @@ fn main() -> Result<()> {
     let nvs_partition = EspDefaultNvsPartition::take()?;
 
     // Create NVS namespace for our time data
-    let nvs = EspNvs::new(nvs_partition.clone(), "sprinklers", true)?;
+    let mut nvs = EspNvs::new(nvs_partition.clone(), "sprinklers", true)?;
 
     // Read saved time offset from NVS (or default to 0)
     let saved_offset: i64 = nvs.get_i64("time_offset")?.unwrap_or(0);
     info!("Loaded time offset from NVS: {}", saved_offset);
 
+    record_reset_reason(&mut nvs);
+
     // Shared time offset (Arc<Mutex> so handlers can access it)
     let time_offset: Arc<Mutex<i64>> = Arc::new(Mutex::new(saved_offset));
     let nvs = Arc::new(Mutex::new(nvs));
@@ -75,6 +104,8 @@ fn main() -> Result<()> {
     //     peripherals.pins.gpio33,
     //     peripherals.pins.gpio25,
     //     peripherals.pins.gpio26,
+    //     Some(peripherals.pins.gpio27.downgrade()), // Well pump relay
+    //     Some(peripherals.pins.gpio34.downgrade_input()), // Flow sensor
     //     &nvs.lock().unwrap(), // Pass NVS reference for loading
     // );
 
@@ -82,6 +113,8 @@ fn main() -> Result<()> {
         peripherals.pins.gpio32,
         peripherals.pins.gpio33,
         peripherals.pins.gpio25,
+        None,                 // No master valve
+        None,                 // No flow sensor
         &nvs.lock().unwrap(), // Pass NVS reference for loading
     );
 
@@ -89,6 +122,18 @@ fn main() -> Result<()> {
 
     println!("Server awaiting connection at http://192.168.1.1");
 
+    // Fed every tick. If the loop stalls (e.g. stuck on a mutex) the watchdog interrupt closes
+    // the valves, see `esp_task_wdt_isr_user_handler`, and the panic resets the chip.
+    let mut watchdog_driver = TWDTDriver::new(
+        peripherals.twdt,
+        &TWDTConfig {
+            duration: WATCHDOG_TIMEOUT,
+            panic_on_trigger: true,
+            ..Default::default()
+        },
+    )?;
+    let mut watchdog = watchdog_driver.watch_current_task()?;
+
     // We keep track of the time here for potential reboot
     let mut last_nvs_save: i64 = 0;
 
@@ -96,6 +141,7 @@ fn main() -> Result<()> {
         {
             led.lock().unwrap().toggle().unwrap();
         }
+        watchdog.feed().ok();
 
         // Get current time offset
         let offset = *time_offset.lock().unwrap();
@@ -110,7 +156,7 @@ fn main() -> Result<()> {
         }
 
         // Non-blocking update - just checks time and toggles if needed
-        aspersores.update_all(offset);
+        aspersores.update_all(offset, &nvs);
 
         // Short delay, doesn't block HTTP
         let delay = Delay::new_default();
@@ -214,27 +260,89 @@ pub fn wifi(
     Ok(Box::new(esp_wifi))
 }
 
-struct Aspersor<'a, T: Pin> {
-    name: String,
-    pin: Arc<Mutex<PinDriver<'a, T, InputOutput>>>,
-    /// Time in seconds from midnight to start the pin
-    init_time: Arc<Mutex<u32>>,
-    /// Duration in seconds for the pin to be set as high
-    duration: Arc<Mutex<u32>>,
+/// Valve outputs `fail_safe` drives low, one bit per GPIO number in two banks of 32
+static FAIL_SAFE_PINS: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Have `fail_safe` close the valve on GPIO `pin`
+fn register_fail_safe_pin(pin: i32) {
+    FAIL_SAFE_PINS[pin as usize / 32].fetch_or(1 << (pin % 32), Ordering::Relaxed);
 }
 
-impl<'a, T: Pin> Clone for Aspersor<'a, T> {
-    fn clone(&self) -> Self {
-        Self {
-            name: self.name.clone(),
-            pin: self.pin.clone(),
-            init_time: self.init_time.clone(),
-            duration: self.duration.clone(),
+/// Drive every valve output low straight through the GPIO driver, without the pin mutexes a
+/// stalled task may be holding
+fn fail_safe() {
+    for (bank, pins) in FAIL_SAFE_PINS.iter().enumerate() {
+        let pins = pins.load(Ordering::Relaxed);
+        for bit in (0..32).filter(|bit| pins & (1 << bit) != 0) {
+            unsafe {
+                esp_idf_svc::sys::gpio_set_level((bank * 32 + bit) as i32, 0);
+            }
         }
     }
 }
 
-impl<'a, T: Pin + OutputPin + InputPin> Aspersor<'a, T> {
+/// Called by ESP-IDF from the task watchdog interrupt when the main loop stops feeding it,
+/// right before the watchdog panics and resets the chip. `gpio_set_level` is only safe in an
+/// ISR with `CONFIG_GPIO_CTRL_FUNC_IN_IRAM=y`, set in `sdkconfig.defaults`.
+#[no_mangle]
+extern "C" fn esp_task_wdt_isr_user_handler() {
+    fail_safe();
+}
+
+/// Log why the chip booted, and count the resets caused by a watchdog or a panic in NVS
+fn record_reset_reason(nvs: &mut EspNvs<NvsDefault>) {
+    let reason = ResetReason::get();
+    info!("Reset reason: {:?}", reason);
+
+    if !matches!(
+        reason,
+        ResetReason::TaskWatchdog
+            | ResetReason::InterruptWatchdog
+            | ResetReason::Watchdog
+            | ResetReason::Panic
+    ) {
+        return;
+    }
+
+    let fault_resets = nvs.get_u32("fault_resets").ok().flatten().unwrap_or(0) + 1;
+    println!("Reset by a fault, {} so far", fault_resets);
+    if let Err(e) = nvs.set_u32("fault_resets", fault_resets) {
+        println!("NVS save error for fault_resets: {:?}", e);
+    }
+    if let Err(e) = nvs.set_str("last_fault", &format!("{:?}", reason)) {
+        println!("NVS save error for last_fault: {:?}", e);
+    }
+}
+
+#[derive(Clone)]
+struct Aspersor<'a> {
+    name: String,
+    pin: Arc<Mutex<PinDriver<'a, AnyIOPin, InputOutput>>>,
+    /// Start slots (seconds from midnight + duration) for the pin to be set as high, and the
+    /// weekdays they run on. Windows may run past midnight, up to 24h
+    schedule: Arc<Mutex<ZoneSchedule>>,
+    /// Timed manual run (`/run`)
+    manual_run: Arc<Mutex<ManualRun>>,
+    /// `/toggle` asked to open the zone, `update_all` opens it once the master valve is ready
+    open_requested: Arc<Mutex<bool>>,
+    /// Per-zone hold (force on/off) that wins over the schedule, see `/override`
+    zone_override: Arc<Mutex<Option<Override>>>,
+    /// Time the valve was actually open per day, counted by `update_all`
+    runtime: Arc<Mutex<RuntimeLog>>,
+    /// Water the current volume run got so far, counted by `update_all` from the flow sensor
+    delivery: Arc<Mutex<Delivery>>,
+    /// When the valve opened, `None` while closed. Monotonic like the manual runs.
+    on_since: Arc<Mutex<Option<Instant>>>,
+    /// Longest the valve may stay open in one go, checked on every pin write
+    safety: Arc<Mutex<SafetyCutoff>>,
+    /// Times the safety cutoff closed the valve, newest last
+    faults: Arc<Mutex<Vec<Instant>>>,
+}
+
+/// How many safety cutoffs each zone remembers
+const MAX_SAFETY_FAULTS: usize = 5;
+
+impl<'a> Aspersor<'a> {
     // pub fn start(&self) {
     //     let current_time = Utc::now()
     //         .checked_sub_signed(TimeDelta::hours(3))
@@ -260,37 +368,110 @@ impl<'a, T: Pin + OutputPin + InputPin> Aspersor<'a, T> {
     //     }
     // }
 
-    pub fn new_with_settings(name: String, pin: T, duration: u32, init_time: u32) -> Self {
+    pub fn new_with_settings(name: String, pin: impl IOPin, schedule: ZoneSchedule) -> Self {
+        let pin = PinDriver::input_output(pin.downgrade()).unwrap();
+        register_fail_safe_pin(pin.pin());
+
         Aspersor {
             name,
-            pin: Arc::new(Mutex::new(PinDriver::input_output(pin).unwrap())),
-            init_time: Arc::new(Mutex::new(init_time)),
-            duration: Arc::new(Mutex::new(duration)),
+            pin: Arc::new(Mutex::new(pin)),
+            schedule: Arc::new(Mutex::new(schedule)),
+            manual_run: Arc::new(Mutex::new(ManualRun::default())),
+            open_requested: Arc::new(Mutex::new(false)),
+            zone_override: Arc::new(Mutex::new(None)),
+            runtime: Arc::new(Mutex::new(RuntimeLog::default())),
+            delivery: Arc::new(Mutex::new(Delivery::default())),
+            on_since: Arc::new(Mutex::new(None)),
+            safety: Arc::new(Mutex::new(SafetyCutoff::new(schedule::DEFAULT_MAX_ON))),
+            faults: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
-    /// Non-blocking: Call this frequently. It checks time and toggles pin.
-    pub fn update(&self, current_time_secs: u32) {
-        let init_time = *self.init_time.lock().unwrap();
-        let duration = *self.duration.lock().unwrap();
-        let end_time = init_time + duration;
+    /// Time left of the timed manual run, `None` if there is none
+    pub fn manual_run_remaining(&self) -> Option<Duration> {
+        self.manual_run.lock().unwrap().remaining(Instant::now())
+    }
+
+    /// Have `update_all` turn the zone on for `duration`, from its next tick (after the master
+    /// valve lead, if there is one)
+    pub fn start_manual_run(&self, duration: Duration) {
+        self.manual_run
+            .lock()
+            .unwrap()
+            .start(Instant::now(), duration);
+        self.safety.lock().unwrap().rearm();
+        info!("{} manual run for {}s", self.name, duration.as_secs());
+    }
 
-        let mut pin = self.pin.lock().unwrap();
+    pub fn is_on(&self) -> bool {
+        self.pin.lock().unwrap().is_high()
+    }
 
-        // Should be ON if current time is within the schedule window
-        let should_be_on = current_time_secs >= init_time && current_time_secs < end_time;
+    /// A timed manual run or a `/toggle` is waiting for the zone to be on
+    fn requested_on(&self) -> bool {
+        *self.open_requested.lock().unwrap() || self.manual_run_remaining().is_some()
+    }
 
-        if should_be_on && pin.is_low() {
+    /// Clear a timed manual run whose time is up. Returns true if one just ended.
+    pub fn finish_manual_run(&self) -> bool {
+        let finished = self.manual_run.lock().unwrap().finish(Instant::now());
+        if finished {
+            info!("{} manual run finished", self.name);
+        }
+        finished
+    }
+
+    /// Seconds since the valve opened, `None` while closed
+    fn on_for(&self) -> Option<u64> {
+        self.on_since
+            .lock()
+            .unwrap()
+            .map(|since| since.elapsed().as_secs())
+    }
+
+    /// Non-blocking: sets the pin to the state the scheduler wants, if it isn't already.
+    /// Every pin write goes through here, so the safety cutoff applies in any mode.
+    pub fn switch(&self, should_be_on: bool) {
+        let mut pin = self.pin.lock().unwrap();
+        let on_for = self.on_for();
+
+        let mut safety = self.safety.lock().unwrap();
+        let was_tripped = safety.tripped;
+        let on = safety.allow(should_be_on, on_for);
+        if safety.tripped && !was_tripped {
+            println!(
+                "SAFETY: {} was on for {}s, over its {}s limit, closing it",
+                self.name,
+                on_for.unwrap_or(0),
+                safety.max_on
+            );
+            let mut faults = self.faults.lock().unwrap();
+            if faults.len() >= MAX_SAFETY_FAULTS {
+                faults.remove(0);
+            }
+            faults.push(Instant::now());
+        }
+
+        if on && pin.is_low() {
             pin.set_high().ok();
+            *self.on_since.lock().unwrap() = Some(Instant::now());
             info!("{} turned ON", self.name);
-        } else if !should_be_on && pin.is_high() {
+        } else if !on && pin.is_high() {
             pin.set_low().ok();
+            *self.on_since.lock().unwrap() = None;
             info!("{} turned OFF", self.name);
         }
     }
 
-    pub fn toggle_pin(&self, server: &mut EspHttpServer<'a>) {
-        let pin = self.pin.clone();
+    /// Close the valve if it has been open past its safety limit, whatever opened it
+    pub fn check_max_on(&self) {
+        if self.is_on() {
+            self.switch(true);
+        }
+    }
+
+    pub fn toggle_pin(&self, server: &mut EspHttpServer<'a>, has_master: bool) {
+        let zone = self.clone();
 
         unsafe {
             server
@@ -298,7 +479,24 @@ impl<'a, T: Pin + OutputPin + InputPin> Aspersor<'a, T> {
                     &format!("/toggle/{}", self.name),
                     Method::Get,
                     move |request| -> core::result::Result<(), EspIOError> {
-                        pin.lock().unwrap().toggle().unwrap();
+                        // Toggling takes over from a timed run
+                        zone.manual_run.lock().unwrap().cancel();
+
+                        // Toggling a zone still waiting to open cancels the request
+                        let mut open_requested = zone.open_requested.lock().unwrap();
+                        let opening = !zone.is_on() && !*open_requested;
+                        // Opening by hand is a new request, it may open a zone the cutoff closed
+                        if opening {
+                            zone.safety.lock().unwrap().rearm();
+                        }
+                        if opening && has_master {
+                            // `update_all` opens it after the master lead, like a scheduled run
+                            *open_requested = true;
+                        } else {
+                            *open_requested = false;
+                            drop(open_requested);
+                            zone.switch(opening);
+                        }
 
                         let mut response = request.into_response(
                             200,
@@ -323,49 +521,76 @@ impl<'a, T: Pin + OutputPin + InputPin> Aspersor<'a, T> {
         server: &mut EspHttpServer<'a>,
         nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
     ) {
-        let duration = self.duration.clone();
-        let init_time = self.init_time.clone();
+        let schedule = self.schedule.clone();
+        let safety = self.safety.clone();
         let name = self.name.clone();
 
-        // Create short NVS key (max 15 chars)
-        // Use first 10 chars of name + suffix
-        let nvs_key_base: String = name.chars().take(10).collect();
-
         unsafe {
-            let nvs_key = nvs_key_base.clone();
-
             server
                 .fn_handler_nonstatic(
                     &format!("/update_aspersor/{}", self.name),
                     Method::Get,
                     move |request| -> core::result::Result<(), EspIOError> {
                         let uri = request.uri();
-                        let received_duration: u32 =
-                            parse_http_uri(uri, "duration").parse().unwrap_or(0);
-                        let received_init_time: u32 =
-                            parse_http_uri(uri, "init_time").parse().unwrap_or(0);
+
+                        let current = schedule.lock().unwrap().clone();
+                        let received = apply_schedule_params(uri, &current).and_then(|received| {
+                            // Safety limit in seconds, kept apart from the schedule it guards
+                            let max_on = get_query_param(uri, "max_on")
+                                .map(|value| {
+                                    value
+                                        .parse()
+                                        .ok()
+                                        .filter(|max_on| {
+                                            (schedule::MIN_MAX_ON..=schedule::SECONDS_PER_DAY)
+                                                .contains(max_on)
+                                        })
+                                        .ok_or("invalid max_on")
+                                })
+                                .transpose()?;
+                            // The cutoff would close the zone partway through a longer slot
+                            let limit = SafetyCutoff::new(
+                                max_on.unwrap_or_else(|| safety.lock().unwrap().max_on),
+                            );
+                            if received
+                                .slots
+                                .iter()
+                                .any(|slot| !limit.covers(u64::from(slot.duration)))
+                            {
+                                return Err("slot longer than max_on");
+                            }
+                            Result::Ok((received, max_on))
+                        });
+                        let (received, max_on) = match received {
+                            Result::Ok(received) => received,
+                            Err(error) => {
+                                let mut response = request.into_response(
+                                    400,
+                                    Some("Bad Request"),
+                                    &[("Access-Control-Allow-Origin", "*")],
+                                )?;
+                                let json = json!({ "ok": false, "error": error });
+                                response.write_all(json.to_string().as_bytes())?;
+                                return core::result::Result::Ok(());
+                            }
+                        };
 
                         println!(
-                            "Updating {}: duration={}, init_time={}",
-                            name, received_duration, received_init_time
+                            "Updating {}: slots={}, weekdays={:#09b}, interval={:?}",
+                            name,
+                            schedule::encode_slots(&received.slots),
+                            received.weekdays,
+                            received.interval
                         );
 
-                        // Update in memory
-                        *duration.lock().unwrap() = received_duration;
-                        *init_time.lock().unwrap() = received_init_time;
-
-                        // Save to NVS with short keys (max 15 chars)
-                        {
-                            let nvs = nvs.lock().unwrap();
-                            let duration_key = format!("{}_d", nvs_key); // e.g., "toberas_af_d" (12 chars)
-                            let init_key = format!("{}_i", nvs_key); // e.g., "toberas_af_i" (12 chars)
+                        // Save to NVS, then update in memory
+                        save_aspersor_settings(&mut nvs.lock().unwrap(), &name, &received);
+                        *schedule.lock().unwrap() = received;
 
-                            if let Err(e) = nvs.set_u32(&duration_key, received_duration) {
-                                println!("NVS save error for {}: {:?}", duration_key, e);
-                            }
-                            if let Err(e) = nvs.set_u32(&init_key, received_init_time) {
-                                println!("NVS save error for {}: {:?}", init_key, e);
-                            }
+                        if let Some(max_on) = max_on {
+                            println!("Updating {} safety limit: {}s", name, max_on);
+                            save_max_on(&nvs.lock().unwrap(), &name, max_on);
+                            safety.lock().unwrap().max_on = max_on;
                         }
 
                         let mut response = request.into_response(
@@ -382,129 +607,697 @@ impl<'a, T: Pin + OutputPin + InputPin> Aspersor<'a, T> {
         }
     }
 
-    pub fn to_json(&self) -> Value {
+    /// `now` is the local time, used to report the next run day and when cutoffs happened
+    /// `remaining` is the watering time left in today's runs, see `Controller::remaining`
+    /// `delivered` is the liters the running volume run got so far, if there is one
+    pub fn to_json(&self, now: i64, remaining: u32, delivered: Option<f64>) -> Value {
+        let (day, _) = schedule::split_local_timestamp(now);
         let pin = self.pin.lock().unwrap();
+        let schedule = self.schedule.lock().unwrap();
+        let safety = *self.safety.lock().unwrap();
+        // First slot is kept at the top level for older clients
+        let first = schedule.slots.first().copied().unwrap_or(Slot::new(0, 0));
+
         json!({
             "name": self.name,
+            "enabled": schedule.enabled,
             "pin": pin.pin(),
             "on": pin.is_high(),
-            "init_time": *self.init_time.lock().unwrap(),
-            "duration": *self.duration.lock().unwrap(),
+            "init_time": first.init_time,
+            "duration": first.duration,
+            "slots": schedule
+                .slots
+                .iter()
+                .map(|slot| json!({
+                    "init_time": slot.init_time,
+                    "duration": slot.duration,
+                    // e.g. "sr-1800" for 30 min before sunrise, null for a fixed clock time
+                    "start": slot.anchor.encode(),
+                }))
+                .collect::<Vec<_>>(),
+            "weekdays": schedule.weekdays,
+            "interval": schedule.interval.map(|interval| json!({
+                "every_days": interval.every_days,
+                "start": format_day(interval.anchor_day),
+            })),
+            "next_run_day": schedule.next_run_day(day).map(format_day),
+            "cycle_soak": schedule.cycle_soak.map(|cycle_soak| json!({
+                "max_cycle": cycle_soak.max_cycle,
+                "soak": cycle_soak.soak,
+            })),
+            "remaining": remaining,
+            // Liters per run when watering by volume, null when the slot duration decides
+            "volume": schedule.volume,
+            "delivered": delivered,
+            "override": self.zone_override.lock().unwrap().map(|hold| json!({
+                "on": hold.on,
+                "until": hold.until.map(format_local),
+            })),
+            // Seconds left of a timed manual run (`/run`), null if there is none
+            "manual_run_remaining": self.manual_run_remaining().map(|left| left.as_secs()),
+            "runtime": self.runtime_json(day, false),
+            // Safety limit in seconds, whether it is holding the zone closed right now and
+            // when it last cut the zone off
+            "max_on": safety.max_on,
+            "tripped": safety.tripped,
+            "faults": self
+                .faults
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|at| format_local(now - at.elapsed().as_secs() as i64))
+                .collect::<Vec<_>>(),
         })
     }
+
+    /// Seconds the zone was on today, in the last 7 days and this month, plus every day kept
+    /// when `with_days` is set
+    pub fn runtime_json(&self, day: i64, with_days: bool) -> Value {
+        let runtime = self.runtime.lock().unwrap();
+        let mut json = json!({
+            "today": runtime.on_day(day),
+            "week": runtime.last_days(day, 7),
+            "month": runtime.month(day),
+        });
+        if with_days {
+            json["days"] = runtime
+                .days()
+                .iter()
+                .map(|&(day, seconds)| json!({ "day": format_day(day), "seconds": seconds }))
+                .collect();
+        }
+        json
+    }
 }
 
-struct Aspersores2<'a> {
-    toberas_afuera: Aspersor<'a, Gpio32>,
-    rotor_frente: Aspersor<'a, Gpio33>,
-    costado_180: Aspersor<'a, Gpio25>,
+/// Pump relay or master valve that feeds every zone, see `MasterTiming`
+#[derive(Clone)]
+struct MasterValve<'a> {
+    pin: Arc<Mutex<PinDriver<'a, AnyIOPin, InputOutput>>>,
+    timing: Arc<Mutex<MasterTiming>>,
+    /// When the master turned on, `None` while off. Monotonic like the manual runs.
+    on_since: Arc<Mutex<Option<Instant>>>,
+    /// Last time a zone was seen open, the lag counts from here
+    zone_open_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<'a> MasterValve<'a> {
+    pub fn new_with_nvs(pin: AnyIOPin, nvs: &EspNvs<NvsDefault>) -> Self {
+        let timing = MasterTiming {
+            lead: nvs.get_u32("master_lead").ok().flatten().unwrap_or(5),
+            lag: nvs.get_u32("master_lag").ok().flatten().unwrap_or(10),
+        };
+        info!("Loaded master valve timing: {:?}", timing);
+
+        let pin = PinDriver::input_output(pin).unwrap();
+        register_fail_safe_pin(pin.pin());
+
+        MasterValve {
+            pin: Arc::new(Mutex::new(pin)),
+            timing: Arc::new(Mutex::new(timing)),
+            on_since: Arc::new(Mutex::new(None)),
+            zone_open_at: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.pin.lock().unwrap().is_high()
+    }
+
+    /// Seconds since the master turned on, `None` while off
+    fn on_for(&self) -> Option<u64> {
+        self.on_since
+            .lock()
+            .unwrap()
+            .map(|since| since.elapsed().as_secs())
+    }
+
+    fn switch(&self, should_be_on: bool) {
+        let mut pin = self.pin.lock().unwrap();
+
+        if should_be_on && pin.is_low() {
+            pin.set_high().ok();
+            *self.on_since.lock().unwrap() = Some(Instant::now());
+            info!("Master valve turned ON");
+        } else if !should_be_on && pin.is_high() {
+            pin.set_low().ok();
+            *self.on_since.lock().unwrap() = None;
+            info!("Master valve turned OFF");
+        }
+    }
+
+    /// Drive the master for the zones `update_all` wants open, and tell whether closed zones
+    /// may open yet. Call after closing the zones that should be off.
+    fn update(&self, zones: &[&Aspersor], zone_wanted: bool, until_next_run: Option<i64>) -> bool {
+        if zones.iter().any(|zone| zone.is_on()) {
+            *self.zone_open_at.lock().unwrap() = Some(Instant::now());
+        }
+        let since_zone_open = self
+            .zone_open_at
+            .lock()
+            .unwrap()
+            .map(|at| at.elapsed().as_secs());
+
+        let timing = *self.timing.lock().unwrap();
+        self.switch(timing.should_be_on(
+            zone_wanted,
+            until_next_run,
+            since_zone_open,
+            self.on_for(),
+        ));
+        timing.may_open(self.on_for())
+    }
+}
+
+/// Pulse output flow sensor on the main line, counted from a GPIO interrupt
+#[derive(Clone)]
+struct FlowSensor<'a> {
+    /// Kept so the interrupt stays subscribed
+    _pin: Arc<Mutex<PinDriver<'a, AnyInputPin, Input>>>,
+    /// Free running pulse count, bumped by the interrupt
+    pulses: Arc<AtomicU32>,
+    meter: Arc<Mutex<FlowMeter>>,
+    /// `pulses` at the previous `take_pulses`
+    last_count: Arc<Mutex<u32>>,
+}
+
+impl<'a> FlowSensor<'a> {
+    pub fn new_with_nvs(pin: AnyInputPin, nvs: &EspNvs<NvsDefault>) -> Self {
+        // Thousandths of a pulse per liter, NVS has no float type
+        let meter = nvs
+            .get_u32("flow_ppl")
+            .ok()
+            .flatten()
+            .map(|ppl| FlowMeter {
+                pulses_per_liter: f64::from(ppl) / 1000.0,
+            })
+            .filter(FlowMeter::is_valid)
+            .unwrap_or(FlowMeter::DEFAULT);
+        info!("Loaded flow sensor calibration: {:?}", meter);
+
+        let pin_number = pin.pin();
+        let pulses = Arc::new(AtomicU32::new(0));
+        let mut driver = PinDriver::input(pin).unwrap();
+        driver.set_pull(Pull::Up).unwrap();
+        driver.set_interrupt_type(InterruptType::PosEdge).unwrap();
+
+        let counter = pulses.clone();
+        unsafe {
+            driver
+                .subscribe(move || {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    // The HAL disables the interrupt on every trigger, keep counting
+                    esp_idf_svc::sys::gpio_intr_enable(pin_number);
+                })
+                .unwrap();
+        }
+        driver.enable_interrupt().unwrap();
+
+        FlowSensor {
+            _pin: Arc::new(Mutex::new(driver)),
+            pulses,
+            meter: Arc::new(Mutex::new(meter)),
+            last_count: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Pulses since the previous call
+    fn take_pulses(&self) -> u32 {
+        let count = self.pulses.load(Ordering::Relaxed);
+        let last = std::mem::replace(&mut *self.last_count.lock().unwrap(), count);
+        schedule::flow::pulses_since(count, last)
+    }
+}
+
+/// State and HTTP handlers shared by every zone of a controller (`Aspersores1` / `Aspersores2`)
+struct Controller {
     manual_mode: Arc<Mutex<bool>>,
+    /// When manual mode was turned on (local seconds), 0 if not known yet
+    manual_since: Arc<Mutex<i64>>,
+    /// Minutes after which manual mode goes back to auto, 0 = never
+    manual_timeout: Arc<Mutex<u32>>,
+    /// Sequential program and the max valves open at once
+    schedule: Arc<Mutex<ControllerSchedule>>,
+    /// Latest runs kept off by a watering restriction, newest last
+    skipped: Arc<Mutex<Vec<SkippedRun>>>,
+    /// Local time of the previous `update_all`, starts at the time saved before the last reboot
+    last_seen: Arc<Mutex<i64>>,
+    /// Previous `update_all`, zone runtime is counted between ticks
+    last_tick: Arc<Mutex<Option<Instant>>>,
+    /// Last time the runtime counters were written to NVS, and whether they changed since
+    runtime_saved_at: Arc<Mutex<Instant>>,
+    runtime_unsaved: Arc<Mutex<bool>>,
+    /// Automatic runs wait for a synced clock, see `ClockTrust`
+    clock_trust: Arc<Mutex<ClockTrust>>,
 }
 
-impl<'a> Aspersores2<'a> {
-    pub fn new_with_nvs(
-        gpio32: Gpio32,
-        gpio33: Gpio33,
-        gpio25: Gpio25,
-        nvs: &EspNvs<NvsDefault>,
-    ) -> Self {
-        // Default values (in seconds)
-        let (toberas_init, toberas_dur) = load_aspersor_settings(
-            nvs,
-            "toberas_afuera",
-            6 * 3600 + 15 * 60, // 6:15 AM
-            45 * 60,            // 45 minutes
-        );
+/// How often changed runtime counters are written to NVS, to spare the flash
+const RUNTIME_SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
 
-        let (rotor_init, rotor_dur) = load_aspersor_settings(
-            nvs,
-            "rotor_frente",
-            7 * 3600, // 7:00 AM
-            40 * 60,  // 40 minutes
-        );
+/// How many skipped runs `/get_info` remembers
+const MAX_SKIPPED_RUNS: usize = 10;
 
-        let (costado_init, costado_dur) = load_aspersor_settings(
-            nvs,
-            "costado_180",
-            5 * 3600, // 5:00 AM
-            75 * 60,  // 1h 15m
-        );
+/// How many runs `/upcoming` lists without a `count`, and at most
+const DEFAULT_UPCOMING_RUNS: usize = 10;
+const MAX_UPCOMING_RUNS: usize = 50;
 
+/// A scheduled run a watering restriction kept off (or cut short)
+struct SkippedRun {
+    zone: String,
+    /// Start of the run in local seconds, together with `zone` identifies it
+    start: i64,
+    /// The restriction, as written in `/set_restrictions`
+    reason: String,
+}
+
+impl Controller {
+    /// `zones` must be in the same order later passed to `update_all` and
+    /// `register_http_handlers`; it is also the default program order
+    pub fn new_with_nvs(nvs: &EspNvs<NvsDefault>, zones: &[&Aspersor]) -> Self {
         // Load manual_mode from NVS (default to false)
         let saved_manual_mode = nvs.get_u8("manual_mode").ok().flatten().unwrap_or(0) != 0;
         info!("Loaded manual_mode: {}", saved_manual_mode);
 
-        Aspersores2 {
-            toberas_afuera: Aspersor::new_with_settings(
-                "toberas_afuera".to_string(),
-                gpio32,
-                toberas_dur,
-                toberas_init,
-            ),
-            rotor_frente: Aspersor::new_with_settings(
-                "rotor_frente".to_string(),
-                gpio33,
-                rotor_dur,
-                rotor_init,
-            ),
-            costado_180: Aspersor::new_with_settings(
-                "costado_180".to_string(),
-                gpio25,
-                costado_dur,
-                costado_init,
-            ),
+        let manual_since = nvs.get_i64("manual_since").ok().flatten().unwrap_or(0);
+        // Default to a day, so a forgotten manual mode doesn't stop watering for good
+        let manual_timeout = nvs
+            .get_u32("manual_timeout")
+            .ok()
+            .flatten()
+            .unwrap_or(24 * 60);
+        info!(
+            "Loaded manual mode since {} with a {} min timeout",
+            format_local(manual_since),
+            manual_timeout
+        );
+
+        let zone_names: Vec<&str> = zones.iter().map(|zone| zone.name.as_str()).collect();
+        let program = load_program(nvs, &zone_names);
+
+        for zone in zones {
+            *zone.zone_override.lock().unwrap() = load_override(nvs, &zone.name);
+            *zone.runtime.lock().unwrap() = load_runtime(nvs, &zone.name);
+            zone.safety.lock().unwrap().max_on = load_max_on(nvs, &zone.name);
+        }
+
+        // 0 = no limit
+        let max_concurrent = nvs.get_u8("max_open").ok().flatten().unwrap_or(0);
+        info!("Loaded max_concurrent: {}", max_concurrent);
+
+        // 0 = zones may open in the same tick another one closes
+        let zone_gap = nvs.get_u32("zone_gap").ok().flatten().unwrap_or(0);
+        info!("Loaded zone_gap: {} s", zone_gap);
+
+        let budget_percent = nvs.get_u16("budget").ok().flatten().unwrap_or(100);
+        info!("Loaded budget_percent: {}", budget_percent);
+
+        // Local seconds, 0 = no rain delay. Expired delays are ignored by the scheduler.
+        let rain_until = nvs.get_i64("rain_until").ok().flatten().unwrap_or(0);
+        info!("Loaded rain_delay_until: {}", format_local(rain_until));
+
+        let location = load_location(nvs);
+        info!("Loaded location: {:?}", location);
+
+        let mut buf = [0u8; 160];
+        let restrictions = nvs
+            .get_str("restrictions", &mut buf)
+            .ok()
+            .flatten()
+            .and_then(schedule::parse_restrictions)
+            .unwrap_or_default();
+        info!(
+            "Loaded restrictions: {}",
+            schedule::encode_restrictions(&restrictions)
+        );
+
+        let seasons = load_seasons(nvs, &zone_names);
+
+        // Seconds, 0 = don't make up missed runs
+        let catch_up_grace = nvs.get_u32("catchup_grace").ok().flatten().unwrap_or(0);
+        info!("Loaded catch_up_grace: {} s", catch_up_grace);
+
+        // The main loop saves the absolute time every minute, so this is roughly when we went off
+        let saved_time = nvs.get_i64("time_offset").ok().flatten();
+        let last_seen = saved_time.map_or(0, local_timestamp);
+
+        let clock_trust = ClockTrust::at_boot(saved_time);
+        info!("Clock at boot: {}", clock_trust.as_str());
+
+        Controller {
             manual_mode: Arc::new(Mutex::new(saved_manual_mode)), // Use loaded value
+            manual_since: Arc::new(Mutex::new(manual_since)),
+            manual_timeout: Arc::new(Mutex::new(manual_timeout)),
+            schedule: Arc::new(Mutex::new(ControllerSchedule {
+                program,
+                max_concurrent,
+                zone_gap,
+                budget_percent,
+                rain_delay_until: (rain_until > 0).then_some(rain_until),
+                location,
+                restrictions,
+                seasons,
+                catch_up_grace,
+                catch_up: Vec::new(),
+            })),
+            skipped: Arc::new(Mutex::new(Vec::new())),
+            last_seen: Arc::new(Mutex::new(last_seen)),
+            last_tick: Arc::new(Mutex::new(None)),
+            runtime_saved_at: Arc::new(Mutex::new(Instant::now())),
+            runtime_unsaved: Arc::new(Mutex::new(false)),
+            clock_trust: Arc::new(Mutex::new(clock_trust)),
         }
     }
 
-    // pub fn day_execution(&self) {
-    //     let is_manual_mode = *self.manual_mode.lock().unwrap();
-    //     if !is_manual_mode {
-    //         self.costado_180.start();
-    //         self.toberas_afuera.start();
-    //         self.rotor_frente.start();
-    //     }
-    // }
-
     /// Non-blocking: Call this every loop iteration
-    pub fn update_all(&self, time_offset: i64) {
+    pub fn update_all(
+        &self,
+        zones: &[&Aspersor],
+        master: Option<&MasterValve>,
+        flow: Option<&FlowSensor>,
+        time_offset: i64,
+        nvs: &Arc<Mutex<EspNvs<NvsDefault>>>,
+    ) {
+        // Water that went through since the last tick, only volume runs use it
+        let pulses = flow.map_or(0, |flow| flow.take_pulses());
+
+        // Valves left on too long are closed first, in manual mode too
+        for zone in zones {
+            zone.check_max_on();
+        }
+
+        // Timed manual runs end on their own, in manual mode too
+        let finished: Vec<bool> = zones.iter().map(|zone| zone.finish_manual_run()).collect();
+
+        // Get current local day and time in seconds from midnight (UTC-3)
+        let (day, current_time) = local_day_and_time(time_offset);
+        let now = schedule::local_seconds(day, current_time);
+        let last_seen = std::mem::replace(&mut *self.last_seen.lock().unwrap(), now);
+
+        // Before switching anything, so the valves are counted as they were since the last tick
+        self.account_runtime(zones, day, nvs);
+
+        // Expired overrides hand the zone back to its schedule
+        for zone in zones {
+            let mut zone_override = zone.zone_override.lock().unwrap();
+            if zone_override.is_some_and(|hold| !hold.is_active(now)) {
+                info!("{} override expired", zone.name);
+                *zone_override = None;
+            }
+        }
+
+        // The timeout counts on a synced clock, the first sync would jump any other one past it
+        let trusted = self.clock_trust.lock().unwrap().is_trusted();
+        if trusted && *self.manual_mode.lock().unwrap() {
+            self.revert_manual_mode_if_expired(now, nvs);
+        }
+
         let is_manual_mode = *self.manual_mode.lock().unwrap();
         if is_manual_mode {
-            return; // In manual mode, don't auto-control
+            // In manual mode, don't auto-control: zones stay as toggled, timed runs still run
+            let should_be_on: Vec<bool> = zones
+                .iter()
+                .zip(&finished)
+                .map(|(zone, done)| (zone.is_on() && !done) || zone.requested_on())
+                .collect();
+            Self::drive(zones, &should_be_on, master, None);
+            return;
+        }
+
+        if !trusted {
+            // The schedule would run at a wrong time, only explicit requests open zones.
+            // `last_seen` keeps moving, so syncing a restored clock still catches up on runs.
+            let should_be_on: Vec<bool> = zones
+                .iter()
+                .map(|zone| {
+                    let held_on = zone
+                        .zone_override
+                        .lock()
+                        .unwrap()
+                        .is_some_and(|hold| hold.apply(false, now));
+                    held_on || zone.requested_on()
+                })
+                .collect();
+            Self::drive(zones, &should_be_on, master, None);
+            return;
+        }
+
+        let schedules: Vec<ZoneSchedule> = zones
+            .iter()
+            .map(|zone| zone.schedule.lock().unwrap().clone())
+            .collect();
+
+        // The clock jumped forward, after a power cut this happens once the time is synced again
+        if now - last_seen > 60 {
+            self.catch_up_missed(zones, &schedules, day, last_seen, now);
+        }
+
+        // Runs are already delayed to respect max_concurrent, queued zones just wait their turn
+        let settings = self.schedule.lock().unwrap().clone();
+        let runs = settings.runs(day, &schedules);
+
+        // Catch-up runs are done once over, or dropped if a rain delay filtered them out
+        self.schedule.lock().unwrap().catch_up.retain(|pending| {
+            runs.iter().any(|run| {
+                run.zone == pending.zone
+                    && run.requested_start == pending.requested_start
+                    && run.end() > now
+            })
+        });
+        let mut should_be_on: Vec<bool> = (0..zones.len())
+            .map(|i| runs.iter().any(|run| run.zone == i && run.is_active(now)))
+            .collect();
+
+        if let Some(flow) = flow {
+            Self::close_delivered(zones, &runs, now, flow, pulses, &mut should_be_on);
+        }
+
+        // Restrictions are checked right before energizing, so they also cut running zones off
+        if let Some(restriction) = settings.restriction_at(now) {
+            for run in runs.iter().filter(|run| run.is_active(now)) {
+                self.report_skipped(&zones[run.zone].name, run.start, restriction);
+            }
+            should_be_on.fill(false);
         }
 
-        // Get current time in seconds from midnight (UTC-3)
-        let adjusted = Utc::now() + TimeDelta::seconds(time_offset);
-        let tz = chrono::FixedOffset::west_opt(3 * 3600).unwrap();
-        let current_time = adjusted
-            .with_timezone(&tz)
-            .time()
-            .num_seconds_from_midnight();
+        // Overrides, timed manual runs and `/toggle` are explicit requests and win over the
+        // schedule and restrictions. Runs and toggles are the newest requests, so they go last.
+        for (on, zone) in should_be_on.iter_mut().zip(zones) {
+            if let Some(hold) = *zone.zone_override.lock().unwrap() {
+                *on = hold.apply(*on, now);
+            }
+            *on |= zone.requested_on();
+        }
+
+        // The master valve comes on ahead of the next run that isn't restricted
+        let until_next_run = runs
+            .iter()
+            .filter(|run| run.start > now && settings.restriction_at(run.start).is_none())
+            .map(|run| run.start - now)
+            .min();
 
-        self.costado_180.update(current_time);
-        self.toberas_afuera.update(current_time);
-        self.rotor_frente.update(current_time);
+        Self::drive(zones, &should_be_on, master, until_next_run);
     }
 
-    pub fn register_http_handlers(
+    /// Switch the zones to `should_be_on` and the master valve along with them
+    fn drive(
+        zones: &[&Aspersor],
+        should_be_on: &[bool],
+        master: Option<&MasterValve>,
+        until_next_run: Option<i64>,
+    ) {
+        // Close valves before opening the next ones so sequenced zones never overlap
+        for (zone, _) in zones.iter().zip(should_be_on).filter(|(_, on)| !**on) {
+            zone.switch(false);
+        }
+
+        // Zones wait for the master lead, the master waits for its lag after they close
+        let zone_wanted = should_be_on.iter().any(|on| *on);
+        let may_open = match master {
+            Some(master) => master.update(zones, zone_wanted, until_next_run),
+            None => true,
+        };
+        if !may_open {
+            return;
+        }
+
+        for (zone, _) in zones.iter().zip(should_be_on).filter(|(_, on)| **on) {
+            zone.switch(true);
+            // A toggle is done once the zone opened, from here on it is like any open zone
+            *zone.open_requested.lock().unwrap() = false;
+        }
+    }
+
+    /// Count the `pulses` towards the running volume runs and keep the zones that got their
+    /// volume off. Their run still holds its time slot, the duration being the cap.
+    fn close_delivered(
+        zones: &[&Aspersor],
+        runs: &[schedule::Run],
+        now: i64,
+        flow: &FlowSensor,
+        pulses: u32,
+        should_be_on: &mut [bool],
+    ) {
+        let meter = *flow.meter.lock().unwrap();
+        // Valves as they were since the last tick, when the pulses came in
+        let open_zones = zones.iter().filter(|zone| zone.is_on()).count();
+        let share = schedule::flow::share_pulses(pulses, open_zones);
+
+        for run in runs.iter().filter(|run| run.is_active(now)) {
+            let Some(target_ml) = run.volume_ml else {
+                continue;
+            };
+            let zone = zones[run.zone];
+            let mut delivery = zone.delivery.lock().unwrap();
+            delivery.count(run.start, if zone.is_on() { share } else { 0 });
+
+            if delivery.is_complete(run.start, target_ml, &meter) {
+                if zone.is_on() {
+                    info!(
+                        "{} got its {} ml of the run at {}",
+                        zone.name,
+                        target_ml,
+                        format_local(run.start)
+                    );
+                }
+                should_be_on[run.zone] = false;
+            }
+        }
+    }
+
+    /// Back to auto mode once the manual mode timeout is over
+    fn revert_manual_mode_if_expired(&self, now: i64, nvs: &Arc<Mutex<EspNvs<NvsDefault>>>) {
+        {
+            // Manual mode from before the start time was recorded counts from now
+            let mut since = self.manual_since.lock().unwrap();
+            if *since == 0 {
+                *since = now;
+            }
+        }
+
+        let Some(deadline) = schedule::manual_mode_expired(
+            *self.manual_mode.lock().unwrap(),
+            *self.manual_since.lock().unwrap(),
+            *self.manual_timeout.lock().unwrap(),
+            now,
+        ) else {
+            return;
+        };
+
+        *self.manual_mode.lock().unwrap() = false;
+        info!(
+            "Manual mode timed out at {}, back to auto",
+            format_local(deadline)
+        );
+
+        let nvs = nvs.lock().unwrap();
+        if let Err(e) = nvs.set_u8("manual_mode", 0) {
+            println!("Failed to save manual_mode: {:?}", e);
+        }
+    }
+
+    /// Add the time since the last tick to every open zone, however it was opened, and save
+    /// the counters every `RUNTIME_SAVE_INTERVAL`
+    fn account_runtime(&self, zones: &[&Aspersor], day: i64, nvs: &Arc<Mutex<EspNvs<NvsDefault>>>) {
+        let tick = Instant::now();
+        let last_tick = self.last_tick.lock().unwrap().replace(tick);
+        if let Some(last_tick) = last_tick {
+            let millis = (tick - last_tick).as_millis().min(u128::from(u32::MAX)) as u32;
+            for zone in zones.iter().filter(|zone| zone.is_on()) {
+                zone.runtime.lock().unwrap().add(day, millis);
+                *self.runtime_unsaved.lock().unwrap() = true;
+            }
+        }
+
+        let mut saved_at = self.runtime_saved_at.lock().unwrap();
+        let mut unsaved = self.runtime_unsaved.lock().unwrap();
+        if !*unsaved || tick - *saved_at < RUNTIME_SAVE_INTERVAL {
+            return;
+        }
+
+        let mut nvs = nvs.lock().unwrap();
+        for zone in zones {
+            save_runtime(&mut nvs, &zone.name, &zone.runtime.lock().unwrap());
+        }
+        *saved_at = tick;
+        *unsaved = false;
+    }
+
+    /// Queue the runs missed between `last_seen` and `now`, if catch-up is on
+    fn catch_up_missed(
+        &self,
+        zones: &[&Aspersor],
+        schedules: &[ZoneSchedule],
+        day: i64,
+        last_seen: i64,
+        now: i64,
+    ) {
+        let mut settings = self.schedule.lock().unwrap();
+        if settings.catch_up_grace == 0 {
+            return;
+        }
+
+        let planned = settings.scheduled_runs(day, schedules);
+        let missed = schedule::catch_up_runs(&planned, last_seen, now, settings.catch_up_grace);
+        for run in &missed {
+            info!(
+                "{} missed its run while off since {}, catching up at {}",
+                zones[run.zone].name,
+                format_local(last_seen),
+                format_local(run.start)
+            );
+        }
+        settings.catch_up.extend(missed);
+    }
+
+    /// Log a run kept off by `restriction`, once per run
+    fn report_skipped(&self, zone: &str, start: i64, restriction: &schedule::Restriction) {
+        let mut skipped = self.skipped.lock().unwrap();
+        if skipped
+            .iter()
+            .any(|run| run.zone == zone && run.start == start)
+        {
+            return;
+        }
+
+        let reason = restriction.encode();
+        println!(
+            "Skipping {} run of {}: restricted by {}",
+            zone,
+            format_local(start),
+            reason
+        );
+
+        if skipped.len() >= MAX_SKIPPED_RUNS {
+            skipped.remove(0);
+        }
+        skipped.push(SkippedRun {
+            zone: zone.to_string(),
+            start,
+            reason,
+        });
+    }
+
+    pub fn register_http_handlers<'a>(
         &self,
         server: &mut EspHttpServer<'a>,
+        zones: &[&Aspersor<'a>],
+        master: Option<&MasterValve<'a>>,
+        flow: Option<&FlowSensor<'a>>,
         time_offset: Arc<Mutex<i64>>,
         nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
     ) {
-        self.costado_180.toggle_pin(server);
-        self.toberas_afuera.toggle_pin(server);
-        self.rotor_frente.toggle_pin(server);
+        for zone in zones {
+            zone.toggle_pin(server, master.is_some());
+            zone.update_duration_and_init_time(server, nvs.clone());
+        }
 
-        self.costado_180
-            .update_duration_and_init_time(server, nvs.clone());
-        self.toberas_afuera
-            .update_duration_and_init_time(server, nvs.clone());
-        self.rotor_frente
-            .update_duration_and_init_time(server, nvs.clone());
+        let zone_names: Vec<String> = zones.iter().map(|zone| zone.name.clone()).collect();
 
         unsafe {
             let manual_mode = self.manual_mode.clone();
+            let manual_since = self.manual_since.clone();
+            let clock_trust_for_manual = self.clock_trust.clone();
+            let time_offset_for_manual = time_offset.clone();
             let nvs_for_manual = nvs.clone();
 
             server
@@ -515,6 +1308,16 @@ impl<'a> Aspersores2<'a> {
                         let mut manual_mode = manual_mode.lock().unwrap();
                         *manual_mode = !(*manual_mode);
 
+                        // Remember when manual mode started, for the auto-revert timeout. Without
+                        // a synced clock it counts from the first tick after the sync.
+                        let trusted = clock_trust_for_manual.lock().unwrap().is_trusted();
+                        let since = if *manual_mode && trusted {
+                            local_now(*time_offset_for_manual.lock().unwrap())
+                        } else {
+                            0
+                        };
+                        *manual_since.lock().unwrap() = since;
+
                         // Save to NVS
                         {
                             let nvs = nvs_for_manual.lock().unwrap();
@@ -522,6 +1325,9 @@ impl<'a> Aspersores2<'a> {
                             if let Err(e) = nvs.set_u8("manual_mode", value) {
                                 println!("Failed to save manual_mode: {:?}", e);
                             }
+                            if let Err(e) = nvs.set_i64("manual_since", since) {
+                                println!("Failed to save manual_since: {:?}", e);
+                            }
                         }
 
                         let mut response = request.into_response(
@@ -541,313 +1347,761 @@ impl<'a> Aspersores2<'a> {
                 .unwrap();
 
             let manual_mode = self.manual_mode.clone();
-            let costado_180 = self.costado_180.clone();
-            let toberas_afuera = self.toberas_afuera.clone();
-            let rotor_frente = self.rotor_frente.clone();
+            let manual_since = self.manual_since.clone();
+            let manual_timeout = self.manual_timeout.clone();
+            let controller_schedule = self.schedule.clone();
+            let skipped = self.skipped.clone();
+            let zones_for_info: Vec<Aspersor<'a>> =
+                zones.iter().map(|zone| (*zone).clone()).collect();
+            let zone_names_for_info = zone_names.clone();
+            let master_for_info = master.cloned();
+            let flow_for_info = flow.cloned();
+            let clock_trust_for_info = self.clock_trust.clone();
+            // Recorded at boot by `record_reset_reason`, fixed until the next one
+            let reset = {
+                let nvs = nvs.lock().unwrap();
+                let mut buf = [0u8; 32];
+                json!({
+                    "reason": format!("{:?}", ResetReason::get()),
+                    "fault_resets": nvs.get_u32("fault_resets").ok().flatten().unwrap_or(0),
+                    "last_fault": nvs.get_str("last_fault", &mut buf).ok().flatten(),
+                })
+            };
+            let time_offset_for_info = time_offset.clone();
 
             server
                 .fn_handler_nonstatic(
                     "/get_info",
                     Method::Get,
                     move |request| -> core::result::Result<(), EspIOError> {
-                        let mut response = request.into_response(200, Some("OK"), &[
-                            ("Access-Control-Allow-Origin", "*")
-                        ])?;
+                        let mut response = request.into_response(
+                            200,
+                            Some("OK"),
+                            &[("Access-Control-Allow-Origin", "*")],
+                        )?;
+
+                        // Use time offset for correct time display
+                        let offset = *time_offset_for_info.lock().unwrap();
+                        let adjusted_time = Utc::now() + TimeDelta::seconds(offset);
+                        let tz = chrono::FixedOffset::west_opt(3 * 3600).unwrap();
+                        let (day, current_time) = local_day_and_time(offset);
+                        let now = schedule::local_seconds(day, current_time);
+
+                        let schedules: Vec<ZoneSchedule> = zones_for_info
+                            .iter()
+                            .map(|zone| zone.schedule.lock().unwrap().clone())
+                            .collect();
+                        let controller_schedule = controller_schedule.lock().unwrap().clone();
+                        let program = &controller_schedule.program;
+                        let runs = controller_schedule.runs(day, &schedules);
+
+                        // When manual mode goes back to auto, null if it is off or never does
+                        let manual_mode_until = schedule::manual_mode_deadline(
+                            *manual_mode.lock().unwrap(),
+                            *manual_since.lock().unwrap(),
+                            *manual_timeout.lock().unwrap(),
+                        )
+                        .map(format_local);
+
+                        // Pump or master valve, null if the controller has none
+                        let master = master_for_info.as_ref().map(|master| {
+                            let timing = *master.timing.lock().unwrap();
+                            json!({ "on": master.is_on(), "lead": timing.lead, "lag": timing.lag })
+                        });
+
+                        // Flow sensor calibration and total pulses, null if there is no sensor
+                        let flow_meter = flow_for_info
+                            .as_ref()
+                            .map(|flow| *flow.meter.lock().unwrap());
+                        let flow = flow_for_info.as_ref().zip(flow_meter).map(|(flow, meter)| {
+                            json!({
+                                "pulses_per_liter": meter.pulses_per_liter,
+                                "pulses": flow.pulses.load(Ordering::Relaxed),
+                            })
+                        });
+                        // Liters the running volume run of each zone got so far
+                        let delivered: Vec<Option<f64>> = zones_for_info
+                            .iter()
+                            .enumerate()
+                            .map(|(i, zone)| {
+                                let meter = flow_meter?;
+                                let run = runs.iter().find(|run| {
+                                    run.zone == i && run.is_active(now) && run.volume_ml.is_some()
+                                })?;
+                                let ml = zone.delivery.lock().unwrap().milliliters(run.start, &meter);
+                                Some(ml as f64 / 1000.0)
+                            })
+                            .collect();
+
+                        let clock_trust = *clock_trust_for_info.lock().unwrap();
 
                         let json = json!({
-                            "time": format!("{}", Utc::now().checked_sub_signed(TimeDelta::hours(3)).unwrap()),
+                            "time": format!("{}", adjusted_time.with_timezone(&tz)),
+                            // never_synced / restored / synced, automatic runs need synced
+                            "clock": clock_trust.as_str(),
+                            "clock_warning": clock_warning(clock_trust),
                             "manual_mode": *manual_mode.lock().unwrap(),
-                            "aspersores": [
-                                costado_180.to_json(),
-                                toberas_afuera.to_json(),
-                                rotor_frente.to_json(),
-                            ]
+                            "manual_timeout": *manual_timeout.lock().unwrap(),
+                            "manual_mode_until": manual_mode_until,
+                            "program": {
+                                "enabled": program.enabled,
+                                "start_time": program.start_time,
+                                "order": program
+                                    .order
+                                    .iter()
+                                    .map(|&i| zones_for_info[i].name.as_str())
+                                    .collect::<Vec<_>>(),
+                                // Today's sequence, after skipping zones that don't run today
+                                "windows": controller_schedule
+                                    .program_on(day)
+                                    .runs(
+                                        day,
+                                        &controller_schedule.zones_on(day, &schedules),
+                                        controller_schedule.zone_gap,
+                                    )
+                                    .iter()
+                                    .map(|run| json!({
+                                        "name": zones_for_info[run.zone].name,
+                                        "init_time": schedule::split_local_timestamp(run.start).1,
+                                        "duration": run.duration,
+                                    }))
+                                    .collect::<Vec<_>>(),
+                            },
+                            "max_concurrent": controller_schedule.max_concurrent,
+                            "zone_gap": controller_schedule.zone_gap,
+                            "budget_percent": controller_schedule.budget_percent,
+                            "rain_delay_until": controller_schedule.rain_delay(now).map(format_local),
+                            "location": {
+                                "latitude": controller_schedule.location.latitude,
+                                "longitude": controller_schedule.location.longitude,
+                            },
+                            "restrictions": schedule::encode_restrictions(&controller_schedule.restrictions),
+                            "seasons": controller_schedule
+                                .seasons
+                                .iter()
+                                .map(|season| season.encode(&zone_names_for_info))
+                                .collect::<Vec<_>>(),
+                            // Season in effect today, null when the zones use their own slots
+                            "season": controller_schedule.season_at(day).map(|season| season.name.as_str()),
+                            "restricted_now": controller_schedule.restriction_at(now).map(|r| r.encode()),
+                            "master": master,
+                            "flow": flow,
+                            "reset": reset,
+                            "catch_up_grace": controller_schedule.catch_up_grace,
+                            // Make-up runs for a power cut, waiting or running
+                            "catch_up": controller_schedule
+                                .catch_up
+                                .iter()
+                                .map(|run| json!({
+                                    "name": zones_for_info[run.zone].name,
+                                    "start": format_local(run.start),
+                                    "duration": run.duration,
+                                }))
+                                .collect::<Vec<_>>(),
+                            "skipped": skipped
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .map(|run| json!({
+                                    "name": run.zone,
+                                    "start": format_local(run.start),
+                                    "reason": run.reason,
+                                }))
+                                .collect::<Vec<_>>(),
+                            // Today's sunrise and sunset in seconds from midnight
+                            "sun": controller_schedule.location.sun_times(day).map(|sun| json!({
+                                "sunrise": sun.sunrise,
+                                "sunset": sun.sunset,
+                            })),
+                            // Zones waiting for a free valve slot, times in seconds from midnight
+                            "queued": runs
+                                .iter()
+                                .filter(|run| run.is_queued(now))
+                                .map(|run| json!({
+                                    "name": zones_for_info[run.zone].name,
+                                    "requested_start": schedule::split_local_timestamp(run.requested_start).1,
+                                    "start": schedule::split_local_timestamp(run.start).1,
+                                }))
+                                .collect::<Vec<_>>(),
+                            "aspersores": zones_for_info
+                                .iter()
+                                .enumerate()
+                                .map(|(i, zone)| zone.to_json(now, remaining(&runs, i, now), delivered[i]))
+                                .collect::<Vec<_>>(),
                         });
-                        let data = json.to_string();
-                        let data = data.as_bytes();
-                        response.write_all(data)?;
+                        response.write_all(json.to_string().as_bytes())?;
 
                         core::result::Result::Ok(())
                     },
                 )
                 .unwrap();
 
-            let time_offset_for_sync = time_offset.clone();
+            // Read-only forecast of the next runs, e.g. /upcoming?count=10
+            let controller_schedule = self.schedule.clone();
+            let zones_for_upcoming: Vec<Aspersor<'a>> =
+                zones.iter().map(|zone| (*zone).clone()).collect();
+            let time_offset_for_upcoming = time_offset.clone();
 
             server
                 .fn_handler_nonstatic(
-                    "/",
+                    "/upcoming",
                     Method::Get,
                     move |request| -> core::result::Result<(), EspIOError> {
+                        let count = match get_query_param(request.uri(), "count") {
+                            None => DEFAULT_UPCOMING_RUNS,
+                            Some(value) => match value.parse::<usize>() {
+                                Result::Ok(count) if (1..=MAX_UPCOMING_RUNS).contains(&count) => {
+                                    count
+                                }
+                                _ => {
+                                    let mut response = request.into_response(
+                                        400,
+                                        Some("Bad Request"),
+                                        &[("Access-Control-Allow-Origin", "*")],
+                                    )?;
+                                    let json = json!({ "ok": false, "error": "invalid count" });
+                                    response.write_all(json.to_string().as_bytes())?;
+                                    return core::result::Result::Ok(());
+                                }
+                            },
+                        };
+
+                        let now = local_now(*time_offset_for_upcoming.lock().unwrap());
+                        let schedules: Vec<ZoneSchedule> = zones_for_upcoming
+                            .iter()
+                            .map(|zone| zone.schedule.lock().unwrap().clone())
+                            .collect();
+                        let controller_schedule = controller_schedule.lock().unwrap().clone();
+
+                        let json = json!({
+                            "ok": true,
+                            "runs": controller_schedule
+                                .upcoming(now, &schedules, count)
+                                .iter()
+                                .map(|(run, reason)| json!({
+                                    "name": zones_for_upcoming[run.zone].name,
+                                    "start": format_local(run.start),
+                                    "end": format_local(run.end()),
+                                    "reason": reason.as_str(),
+                                    // A restriction at the start keeps the run off, null if none
+                                    "restricted": controller_schedule
+                                        .restriction_at(run.start)
+                                        .map(|restriction| restriction.encode()),
+                                }))
+                                .collect::<Vec<_>>(),
+                        });
+
                         let mut response = request.into_response(
                             200,
                             Some("OK"),
-                            &[
-                                ("Content-Type", "text/html; charset=utf-8"),
-                                ("Access-Control-Allow-Origin", "*"),
-                            ],
+                            &[("Access-Control-Allow-Origin", "*")],
                         )?;
-
-                        // Apply the saved time offset!
-                        let offset = *time_offset_for_sync.lock().unwrap();
-                        let adjusted_time = Utc::now() + TimeDelta::seconds(offset);
-                        let tz = chrono::FixedOffset::west_opt(3 * 3600).unwrap();
-                        let server_time = format!("{}", adjusted_time.with_timezone(&tz));
-
-                        let html = root_html::get_root_html(&server_time);
-                        response.write_all(html.as_bytes())?;
-
+                        response.write_all(json.to_string().as_bytes())?;
                         core::result::Result::Ok(())
                     },
                 )
                 .unwrap();
 
-            // Clone the Arc's for the handler
-            let time_offset_clone = time_offset.clone();
-            let nvs_clone = nvs.clone();
+            let controller_schedule = self.schedule.clone();
+            let nvs_for_program = nvs.clone();
 
             server
                 .fn_handler_nonstatic(
-                    "/set_time",
+                    "/set_program",
                     Method::Get,
                     move |request| -> core::result::Result<(), EspIOError> {
                         let uri = request.uri();
-                        let timestamp_str = parse_http_uri(uri, "timestamp");
-                        let client_timestamp: i64 = timestamp_str.parse().unwrap_or(0);
 
-                        // Calculate offset: client_time - our_boot_time
-                        let our_time = Utc::now().timestamp();
-                        let offset = client_timestamp - our_time;
+                        let current = controller_schedule.lock().unwrap().program.clone();
+                        let received = match apply_program_params(uri, &current, &zone_names) {
+                            Result::Ok(received) => received,
+                            Err(error) => {
+                                let mut response = request.into_response(
+                                    400,
+                                    Some("Bad Request"),
+                                    &[("Access-Control-Allow-Origin", "*")],
+                                )?;
+                                let json = json!({ "ok": false, "error": error });
+                                response.write_all(json.to_string().as_bytes())?;
+                                return core::result::Result::Ok(());
+                            }
+                        };
 
-                        // Save to memory
-                        *time_offset_clone.lock().unwrap() = offset;
+                        println!("Updating program: {:?}", received);
 
-                        // Save to NVS (persists across reboots!)
-                        {
-                            let nvs = nvs_clone.lock().unwrap();
-                            nvs.set_i64("time_offset", offset).ok();
-                        }
-
-                        info!("Time synced! Offset: {} seconds", offset);
+                        // Save to NVS, then update in memory
+                        save_program(&mut nvs_for_program.lock().unwrap(), &received, &zone_names);
+                        controller_schedule.lock().unwrap().program = received;
 
                         let mut response = request.into_response(
                             200,
                             Some("OK"),
                             &[("Access-Control-Allow-Origin", "*")],
                         )?;
-
-                        let json = json!({ "ok": true });
-                        response.write_all(json.to_string().as_bytes())?;
+                        let json = json!({ "ok": true }).to_string();
+                        response.write_all(json.as_bytes())?;
                         core::result::Result::Ok(())
                     },
                 )
                 .unwrap();
-        }
-    }
-}
 
-fn parse_http_uri<'a>(uri: &'a str, param: &str) -> &'a str {
-    //TODO!: start reading from the '?' to avoid conflicts with route having the same sub-string
-    let i = uri.find(param).unwrap();
-    let initial_i = i + param.len() + 1;
-    let partial_uri = &uri[initial_i..];
-    let final_i = initial_i + partial_uri.find("&").unwrap_or(partial_uri.len());
+            // Controller-wide settings that are not part of the program
+            let controller_schedule = self.schedule.clone();
+            let manual_timeout = self.manual_timeout.clone();
+            let master_for_controller = master.cloned();
+            let flow_for_controller = flow.cloned();
+            let nvs_for_controller = nvs.clone();
 
-    &uri[initial_i..final_i]
-}
+            server
+                .fn_handler_nonstatic(
+                    "/set_controller",
+                    Method::Get,
+                    move |request| -> core::result::Result<(), EspIOError> {
+                        let uri = request.uri();
 
-/// **Not recomended pins: 6 - 11, 16 - 17
-struct Aspersores1<'a> {
-    microaspersores_frente: Aspersor<'a, Gpio32>,
-    goteros: Aspersor<'a, Gpio33>,
-    atras_360: Aspersor<'a, Gpio25>,
-    atras_pileta: Aspersor<'a, Gpio26>,
-    manual_mode: Arc<Mutex<bool>>,
-}
+                        let current = ControllerSettings::of(&controller_schedule.lock().unwrap());
+                        let received = apply_controller_params(uri, current).and_then(|received| {
+                            // Minutes before manual mode goes back to auto, 0 = never
+                            let timeout = get_query_param(uri, "manual_timeout")
+                                .map(|value| value.parse::<u32>().map_err(|_| "invalid manual_timeout"))
+                                .transpose()?;
+                            let master_timing = match &master_for_controller {
+                                Some(master) => {
+                                    Some(apply_master_params(uri, *master.timing.lock().unwrap())?)
+                                }
+                                None if get_query_param(uri, "master_lead").is_some()
+                                    || get_query_param(uri, "master_lag").is_some() =>
+                                {
+                                    return Err("no master valve");
+                                }
+                                None => None,
+                            };
+                            let flow_meter = match (&flow_for_controller, get_query_param(uri, "pulses_per_liter")) {
+                                (Some(_), Some(value)) => Some(parse_flow_meter(value)?),
+                                (None, Some(_)) => return Err("no flow sensor"),
+                                (_, None) => None,
+                            };
+                            Result::Ok((received, timeout, master_timing, flow_meter))
+                        });
+                        let (received, timeout, master_timing, flow_meter) = match received {
+                            Result::Ok(received) => received,
+                            Err(error) => {
+                                let mut response = request.into_response(
+                                    400,
+                                    Some("Bad Request"),
+                                    &[("Access-Control-Allow-Origin", "*")],
+                                )?;
+                                let json = json!({ "ok": false, "error": error });
+                                response.write_all(json.to_string().as_bytes())?;
+                                return core::result::Result::Ok(());
+                            }
+                        };
 
-impl<'a> Aspersores1<'a> {
-    pub fn new_with_nvs(
-        gpio32: Gpio32,
-        gpio33: Gpio33,
-        gpio25: Gpio25,
-        gpio26: Gpio26,
-        nvs: &EspNvs<NvsDefault>,
-    ) -> Self {
-        // Default values (in seconds)
-        let (micro_init, micro_dur) = load_aspersor_settings(
-            nvs,
-            "micro_frente", // shortened to fit NVS 15-char key limit
-            22 * 3600,      // 22:00
-            20 * 60,        // 20 minutes
-        );
+                        println!(
+                            "Updating controller: max_concurrent={}, zone_gap={}, budget_percent={}, location={:?}, catch_up_grace={}",
+                            received.max_concurrent,
+                            received.zone_gap,
+                            received.budget_percent,
+                            received.location,
+                            received.catch_up_grace
+                        );
 
-        let (goteros_init, goteros_dur) = load_aspersor_settings(
-            nvs,
-            "goteros",
-            16 * 3600, // 16:00
-            5 * 3600,  // 5 hours
-        );
+                        // Save to NVS, then update in memory. Only these fields: the main loop
+                        // may have queued catch-up runs or ended a rain delay meanwhile.
+                        save_controller_settings(&nvs_for_controller.lock().unwrap(), &received);
+                        received.apply_to(&mut controller_schedule.lock().unwrap());
 
-        let (atras360_init, atras360_dur) = load_aspersor_settings(
-            nvs,
-            "atras_360",
-            3 * 3600 + 30 * 60, // 3:30 AM
-            90 * 60,            // 1h 30m
-        );
+                        if let Some(timeout) = timeout {
+                            println!("Updating manual_timeout: {} min", timeout);
+                            let nvs = nvs_for_controller.lock().unwrap();
+                            if let Err(e) = nvs.set_u32("manual_timeout", timeout) {
+                                println!("NVS save error for manual_timeout: {:?}", e);
+                            }
+                            *manual_timeout.lock().unwrap() = timeout;
+                        }
 
-        let (pileta_init, pileta_dur) = load_aspersor_settings(
-            nvs,
-            "atras_pileta",
-            21 * 3600, // 21:00
-            60 * 60,   // 1 hour
-        );
+                        if let (Some(timing), Some(master)) = (master_timing, &master_for_controller) {
+                            println!("Updating master valve: {:?}", timing);
+                            let nvs = nvs_for_controller.lock().unwrap();
+                            if let Err(e) = nvs.set_u32("master_lead", timing.lead) {
+                                println!("NVS save error for master_lead: {:?}", e);
+                            }
+                            if let Err(e) = nvs.set_u32("master_lag", timing.lag) {
+                                println!("NVS save error for master_lag: {:?}", e);
+                            }
+                            *master.timing.lock().unwrap() = timing;
+                        }
 
-        // Load manual_mode from NVS (default to false)
-        let saved_manual_mode = nvs.get_u8("manual_mode").ok().flatten().unwrap_or(0) != 0;
-        info!("Loaded manual_mode: {}", saved_manual_mode);
+                        if let (Some(meter), Some(flow)) = (flow_meter, &flow_for_controller) {
+                            println!("Updating flow sensor: {:?}", meter);
+                            let nvs = nvs_for_controller.lock().unwrap();
+                            let ppl = (meter.pulses_per_liter * 1000.0).round() as u32;
+                            if let Err(e) = nvs.set_u32("flow_ppl", ppl) {
+                                println!("NVS save error for flow_ppl: {:?}", e);
+                            }
+                            *flow.meter.lock().unwrap() = meter;
+                        }
 
-        Aspersores1 {
-            microaspersores_frente: Aspersor::new_with_settings(
-                "micro_frente".to_string(), // Use same short name as NVS key
-                gpio32,
-                micro_dur,
-                micro_init,
-            ),
-            goteros: Aspersor::new_with_settings(
-                "goteros".to_string(),
-                gpio33,
-                goteros_dur,
-                goteros_init,
-            ),
-            atras_360: Aspersor::new_with_settings(
-                "atras_360".to_string(),
-                gpio25,
-                atras360_dur,
-                atras360_init,
-            ),
-            atras_pileta: Aspersor::new_with_settings(
-                "atras_pileta".to_string(),
-                gpio26,
-                pileta_dur,
-                pileta_init,
-            ),
-            manual_mode: Arc::new(Mutex::new(saved_manual_mode)), // Use loaded value
-        }
-    }
+                        let mut response = request.into_response(
+                            200,
+                            Some("OK"),
+                            &[("Access-Control-Allow-Origin", "*")],
+                        )?;
+                        let json = json!({ "ok": true }).to_string();
+                        response.write_all(json.as_bytes())?;
+                        core::result::Result::Ok(())
+                    },
+                )
+                .unwrap();
 
-    /// Non-blocking: Call this every loop iteration
-    pub fn update_all(&self, time_offset: i64) {
-        let is_manual_mode = *self.manual_mode.lock().unwrap();
-        if is_manual_mode {
-            return; // In manual mode, don't auto-control
-        }
+            // Watering restrictions, e.g. ?rules=odd,no:36000-68400@1201-0229 (empty clears)
+            let controller_schedule = self.schedule.clone();
+            let nvs_for_restrictions = nvs.clone();
 
-        // Get current time in seconds from midnight (UTC-3)
-        let adjusted = Utc::now() + TimeDelta::seconds(time_offset);
-        let tz = chrono::FixedOffset::west_opt(3 * 3600).unwrap();
-        let current_time = adjusted
-            .with_timezone(&tz)
-            .time()
-            .num_seconds_from_midnight();
+            server
+                .fn_handler_nonstatic(
+                    "/set_restrictions",
+                    Method::Get,
+                    move |request| -> core::result::Result<(), EspIOError> {
+                        let uri = request.uri();
 
-        self.atras_360.update(current_time);
-        self.atras_pileta.update(current_time);
-        self.microaspersores_frente.update(current_time);
-        self.goteros.update(current_time);
-    }
+                        let Some(restrictions) =
+                            get_query_param(uri, "rules").and_then(schedule::parse_restrictions)
+                        else {
+                            let mut response = request.into_response(
+                                400,
+                                Some("Bad Request"),
+                                &[("Access-Control-Allow-Origin", "*")],
+                            )?;
+                            let json = json!({ "ok": false, "error": "invalid rules" });
+                            response.write_all(json.to_string().as_bytes())?;
+                            return core::result::Result::Ok(());
+                        };
+
+                        let encoded = schedule::encode_restrictions(&restrictions);
+                        println!("Updating restrictions: {}", encoded);
+
+                        // Save to NVS, then update in memory
+                        {
+                            let mut nvs = nvs_for_restrictions.lock().unwrap();
+                            if let Err(e) = nvs.set_str("restrictions", &encoded) {
+                                println!("NVS save error for restrictions: {:?}", e);
+                            }
+                        }
+                        controller_schedule.lock().unwrap().restrictions = restrictions;
 
-    pub fn register_http_handlers(
-        &self,
-        server: &mut EspHttpServer<'a>,
-        time_offset: Arc<Mutex<i64>>, // Add this parameter
-        nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
-    ) {
-        self.microaspersores_frente.toggle_pin(server);
-        self.goteros.toggle_pin(server);
-        self.atras_360.toggle_pin(server);
-        self.atras_pileta.toggle_pin(server);
-
-        self.microaspersores_frente
-            .update_duration_and_init_time(server, nvs.clone());
-        self.goteros
-            .update_duration_and_init_time(server, nvs.clone());
-        self.atras_360
-            .update_duration_and_init_time(server, nvs.clone());
-        self.atras_pileta
-            .update_duration_and_init_time(server, nvs.clone());
+                        let mut response = request.into_response(
+                            200,
+                            Some("OK"),
+                            &[("Access-Control-Allow-Origin", "*")],
+                        )?;
+                        let json = json!({ "ok": true }).to_string();
+                        response.write_all(json.as_bytes())?;
+                        core::result::Result::Ok(())
+                    },
+                )
+                .unwrap();
 
-        unsafe {
-            let manual_mode = self.manual_mode.clone();
-            let nvs_for_manual = nvs.clone();
+            // Runtime per zone: today, last 7 days, this month and the daily history
+            let zones_for_runtime: Vec<Aspersor<'a>> =
+                zones.iter().map(|zone| (*zone).clone()).collect();
+            let time_offset_for_runtime = time_offset.clone();
 
             server
                 .fn_handler_nonstatic(
-                    "/toggle/manual_mode",
+                    "/runtime",
                     Method::Get,
                     move |request| -> core::result::Result<(), EspIOError> {
-                        let mut manual_mode = manual_mode.lock().unwrap();
-                        *manual_mode = !(*manual_mode);
+                        let (day, _) = local_day_and_time(*time_offset_for_runtime.lock().unwrap());
 
-                        // Save to NVS
-                        {
-                            let nvs = nvs_for_manual.lock().unwrap();
-                            let value: u8 = if *manual_mode { 1 } else { 0 };
-                            if let Err(e) = nvs.set_u8("manual_mode", value) {
-                                println!("Failed to save manual_mode: {:?}", e);
+                        let json = json!({
+                            "day": format_day(day),
+                            "zones": zones_for_runtime
+                                .iter()
+                                .map(|zone| {
+                                    let mut json = zone.runtime_json(day, true);
+                                    json["name"] = json!(zone.name);
+                                    json
+                                })
+                                .collect::<Vec<_>>(),
+                        });
+
+                        let mut response = request.into_response(
+                            200,
+                            Some("OK"),
+                            &[("Access-Control-Allow-Origin", "*")],
+                        )?;
+                        response.write_all(json.to_string().as_bytes())?;
+                        core::result::Result::Ok(())
+                    },
+                )
+                .unwrap();
+
+            // Seasonal schedule sets: ?value=summer@1101-0331;program=75600;costado_180=75600:3600
+            // adds or replaces a season by name, ?delete=summer removes it
+            let controller_schedule = self.schedule.clone();
+            let zone_names_for_seasons: Vec<String> =
+                zones.iter().map(|zone| zone.name.clone()).collect();
+            let nvs_for_seasons = nvs.clone();
+
+            server
+                .fn_handler_nonstatic(
+                    "/set_season",
+                    Method::Get,
+                    move |request| -> core::result::Result<(), EspIOError> {
+                        let uri = request.uri();
+
+                        let mut seasons = controller_schedule.lock().unwrap().seasons.clone();
+                        let updated = if let Some(name) = get_query_param(uri, "delete") {
+                            seasons.retain(|season| season.name != name);
+                            Result::Ok(seasons)
+                        } else {
+                            get_query_param(uri, "value")
+                                .and_then(|value| Season::parse(value, &zone_names_for_seasons))
+                                .ok_or("invalid season")
+                                .and_then(|season| {
+                                    // Longer ones would not read back from NVS
+                                    if season.encode(&zone_names_for_seasons).len()
+                                        > schedule::MAX_SEASON_LEN
+                                    {
+                                        return Err("season too long");
+                                    }
+                                    match seasons.iter().position(|s| s.name == season.name) {
+                                        Some(i) => seasons[i] = season,
+                                        None if seasons.len() < schedule::MAX_SEASONS => {
+                                            seasons.push(season)
+                                        }
+                                        None => return Err("too many seasons"),
+                                    }
+                                    Result::Ok(seasons)
+                                })
+                        };
+
+                        let seasons = match updated {
+                            Result::Ok(seasons) => seasons,
+                            Err(error) => {
+                                let mut response = request.into_response(
+                                    400,
+                                    Some("Bad Request"),
+                                    &[("Access-Control-Allow-Origin", "*")],
+                                )?;
+                                let json = json!({ "ok": false, "error": error });
+                                response.write_all(json.to_string().as_bytes())?;
+                                return core::result::Result::Ok(());
                             }
-                        }
+                        };
+
+                        println!(
+                            "Updating seasons: {:?}",
+                            seasons
+                                .iter()
+                                .map(|season| &season.name)
+                                .collect::<Vec<_>>()
+                        );
+
+                        // Save to NVS, then update in memory
+                        save_seasons(
+                            &mut nvs_for_seasons.lock().unwrap(),
+                            &seasons,
+                            &zone_names_for_seasons,
+                        );
+                        controller_schedule.lock().unwrap().seasons = seasons;
 
                         let mut response = request.into_response(
                             200,
                             Some("OK"),
                             &[("Access-Control-Allow-Origin", "*")],
                         )?;
-                        let json = json!({
-                            "ok": true,
-                        })
-                        .to_string();
+                        let json = json!({ "ok": true }).to_string();
                         response.write_all(json.as_bytes())?;
+                        core::result::Result::Ok(())
+                    },
+                )
+                .unwrap();
+
+            // Per-zone override: ?name=<zone>&mode=on|off|auto[&minutes=N], no minutes = until cleared
+            let zones_for_override: Vec<Aspersor<'a>> =
+                zones.iter().map(|zone| (*zone).clone()).collect();
+            let time_offset_for_override = time_offset.clone();
+            let nvs_for_override = nvs.clone();
+
+            server
+                .fn_handler_nonstatic(
+                    "/override",
+                    Method::Get,
+                    move |request| -> core::result::Result<(), EspIOError> {
+                        let uri = request.uri();
+                        let now = local_now(*time_offset_for_override.lock().unwrap());
 
+                        let zone = get_query_param(uri, "name").and_then(|name| {
+                            zones_for_override.iter().find(|zone| zone.name == name)
+                        });
+                        let until = match get_query_param(uri, "minutes") {
+                            Some(minutes) => minutes
+                                .parse::<i64>()
+                                .ok()
+                                .filter(|minutes| (1..=366 * 24 * 60).contains(minutes))
+                                .map(|minutes| Some(now + minutes * 60)),
+                            None => Some(None),
+                        };
+                        let received = match (get_query_param(uri, "mode"), until) {
+                            (Some("on"), Some(until)) => Some(Some(Override { on: true, until })),
+                            (Some("off"), Some(until)) => Some(Some(Override { on: false, until })),
+                            (Some("auto"), _) => Some(None),
+                            _ => None,
+                        };
+
+                        let (Some(zone), Some(received)) = (zone, received) else {
+                            let mut response = request.into_response(
+                                400,
+                                Some("Bad Request"),
+                                &[("Access-Control-Allow-Origin", "*")],
+                            )?;
+                            let json =
+                                json!({ "ok": false, "error": "invalid name, mode or minutes" });
+                            response.write_all(json.to_string().as_bytes())?;
+                            return core::result::Result::Ok(());
+                        };
+
+                        println!("Override for {}: {:?}", zone.name, received);
+
+                        // Save to NVS, then update in memory; update_all applies it next tick
+                        save_override(&nvs_for_override.lock().unwrap(), &zone.name, received);
+                        *zone.zone_override.lock().unwrap() = received;
+
+                        let mut response = request.into_response(
+                            200,
+                            Some("OK"),
+                            &[("Access-Control-Allow-Origin", "*")],
+                        )?;
+                        let json = json!({ "ok": true }).to_string();
+                        response.write_all(json.as_bytes())?;
                         core::result::Result::Ok(())
                     },
                 )
                 .unwrap();
 
-            let manual_mode = self.manual_mode.clone();
-            let microaspersores_frente = self.microaspersores_frente.clone();
-            let goteros = self.goteros.clone();
-            let atras_360 = self.atras_360.clone();
-            let atras_pileta = self.atras_pileta.clone();
-            let time_offset_for_info = time_offset.clone();
+            // Timed manual run: ?name=<zone>&minutes=N, minutes=0 stops it
+            let zones_for_run: Vec<Aspersor<'a>> =
+                zones.iter().map(|zone| (*zone).clone()).collect();
 
             server
                 .fn_handler_nonstatic(
-                    "/get_info",
+                    "/run",
                     Method::Get,
                     move |request| -> core::result::Result<(), EspIOError> {
+                        let uri = request.uri();
+
+                        let zone = get_query_param(uri, "name")
+                            .and_then(|name| zones_for_run.iter().find(|zone| zone.name == name));
+                        let duration =
+                            get_query_param(uri, "minutes").and_then(schedule::manual_run_duration);
+
+                        let (Some(zone), Some(duration)) = (zone, duration) else {
+                            let mut response = request.into_response(
+                                400,
+                                Some("Bad Request"),
+                                &[("Access-Control-Allow-Origin", "*")],
+                            )?;
+                            let json = json!({ "ok": false, "error": "invalid name or minutes" });
+                            response.write_all(json.to_string().as_bytes())?;
+                            return core::result::Result::Ok(());
+                        };
+
+                        if !zone.safety.lock().unwrap().covers(duration.as_secs()) {
+                            let mut response = request.into_response(
+                                400,
+                                Some("Bad Request"),
+                                &[("Access-Control-Allow-Origin", "*")],
+                            )?;
+                            let json = json!({ "ok": false, "error": "minutes over max_on" });
+                            response.write_all(json.to_string().as_bytes())?;
+                            return core::result::Result::Ok(());
+                        }
+
+                        if !duration.is_zero() {
+                            zone.start_manual_run(duration);
+                        } else {
+                            // Stop now; in auto mode the schedule takes over on the next tick
+                            zone.manual_run.lock().unwrap().cancel();
+                            zone.switch(false);
+                        }
+
                         let mut response = request.into_response(
                             200,
                             Some("OK"),
                             &[("Access-Control-Allow-Origin", "*")],
                         )?;
+                        let json = json!({ "ok": true }).to_string();
+                        response.write_all(json.as_bytes())?;
+                        core::result::Result::Ok(())
+                    },
+                )
+                .unwrap();
 
-                        // Use time offset for correct time display
-                        let offset = *time_offset_for_info.lock().unwrap();
-                        let adjusted_time = Utc::now() + TimeDelta::seconds(offset);
-                        let tz = chrono::FixedOffset::west_opt(3 * 3600).unwrap();
+            // Rain delay: ?days=N from now (synced clock), or ?until=<unix timestamp>; days=0 cancels
+            let controller_schedule = self.schedule.clone();
+            let time_offset_for_rain = time_offset.clone();
+            let nvs_for_rain = nvs.clone();
 
-                        let json = json!({
-                            "time": format!("{}", adjusted_time.with_timezone(&tz)),
-                            "manual_mode": *manual_mode.lock().unwrap(),
-                            "aspersores": [
-                                microaspersores_frente.to_json(),
-                                goteros.to_json(),
-                                atras_360.to_json(),
-                                atras_pileta.to_json(),
-                            ]
-                        });
-                        response.write_all(json.to_string().as_bytes())?;
+            server
+                .fn_handler_nonstatic(
+                    "/rain_delay",
+                    Method::Get,
+                    move |request| -> core::result::Result<(), EspIOError> {
+                        let uri = request.uri();
+                        let now = local_now(*time_offset_for_rain.lock().unwrap());
+
+                        let until =
+                            match (get_query_param(uri, "days"), get_query_param(uri, "until")) {
+                                (Some(days), _) => days
+                                    .parse::<u16>()
+                                    .ok()
+                                    .filter(|days| *days <= schedule::MAX_INTERVAL_DAYS)
+                                    .map(|days| now + i64::from(days) * 86400)
+                                    .ok_or("invalid days"),
+                                (None, Some(until)) => until
+                                    .parse::<i64>()
+                                    .ok()
+                                    .map(local_timestamp)
+                                    .ok_or("invalid until"),
+                                (None, None) => Err("missing days or until"),
+                            };
+                        let until = match until {
+                            Result::Ok(until) => (until > now).then_some(until),
+                            Err(error) => {
+                                let mut response = request.into_response(
+                                    400,
+                                    Some("Bad Request"),
+                                    &[("Access-Control-Allow-Origin", "*")],
+                                )?;
+                                let json = json!({ "ok": false, "error": error });
+                                response.write_all(json.to_string().as_bytes())?;
+                                return core::result::Result::Ok(());
+                            }
+                        };
+
+                        match until {
+                            Some(until) => info!("Rain delay until {}", format_local(until)),
+                            None => info!("Rain delay cancelled"),
+                        }
+
+                        // Save to NVS, then update in memory
+                        {
+                            let nvs = nvs_for_rain.lock().unwrap();
+                            if let Err(e) = nvs.set_i64("rain_until", until.unwrap_or(0)) {
+                                println!("NVS save error for rain_until: {:?}", e);
+                            }
+                        }
+                        controller_schedule.lock().unwrap().rain_delay_until = until;
 
+                        let mut response = request.into_response(
+                            200,
+                            Some("OK"),
+                            &[("Access-Control-Allow-Origin", "*")],
+                        )?;
+                        let json = json!({ "ok": true, "until": until.map(format_local) });
+                        response.write_all(json.to_string().as_bytes())?;
                         core::result::Result::Ok(())
                     },
                 )
@@ -855,6 +2109,7 @@ impl<'a> Aspersores1<'a> {
 
             // /Root endpoint
             let time_offset_for_sync = time_offset.clone();
+            let clock_trust_for_root = self.clock_trust.clone();
 
             server
                 .fn_handler_nonstatic(
@@ -870,12 +2125,14 @@ impl<'a> Aspersores1<'a> {
                             ],
                         )?;
 
+                        // Apply the saved time offset!
                         let offset = *time_offset_for_sync.lock().unwrap();
                         let adjusted_time = Utc::now() + TimeDelta::seconds(offset);
                         let tz = chrono::FixedOffset::west_opt(3 * 3600).unwrap();
                         let server_time = format!("{}", adjusted_time.with_timezone(&tz));
 
-                        let html = root_html::get_root_html(&server_time);
+                        let warning = clock_warning(*clock_trust_for_root.lock().unwrap());
+                        let html = root_html::get_root_html(&server_time, warning);
                         response.write_all(html.as_bytes())?;
 
                         core::result::Result::Ok(())
@@ -885,6 +2142,8 @@ impl<'a> Aspersores1<'a> {
 
             // /set_time endpoint
             let time_offset_clone = time_offset.clone();
+            let clock_trust = self.clock_trust.clone();
+            let last_seen = self.last_seen.clone();
             let nvs_clone = nvs.clone();
 
             server
@@ -893,14 +2152,31 @@ impl<'a> Aspersores1<'a> {
                     Method::Get,
                     move |request| -> core::result::Result<(), EspIOError> {
                         let uri = request.uri();
-                        let timestamp_str = parse_http_uri(uri, "timestamp");
-                        let client_timestamp: i64 = timestamp_str.parse().unwrap_or(0);
 
+                        // A missing or unreadable timestamp would set the clock back to 1970
+                        let Some(client_timestamp) = parse_http_uri(uri, "timestamp")
+                            .parse::<i64>()
+                            .ok()
+                            .filter(|timestamp| *timestamp >= schedule::MIN_SYNCED_TIME)
+                        else {
+                            let mut response = request.into_response(
+                                400,
+                                Some("Bad Request"),
+                                &[("Access-Control-Allow-Origin", "*")],
+                            )?;
+                            let json = json!({ "ok": false, "error": "invalid timestamp" });
+                            response.write_all(json.to_string().as_bytes())?;
+                            return core::result::Result::Ok(());
+                        };
+
+                        // Calculate offset: client_time - our_boot_time
                         let our_time = Utc::now().timestamp();
                         let offset = client_timestamp - our_time;
 
+                        // Save to memory
                         *time_offset_clone.lock().unwrap() = offset;
 
+                        // Save to NVS (persists across reboots!)
                         {
                             let nvs = nvs_clone.lock().unwrap();
                             nvs.set_i64("time_offset", offset).ok();
@@ -908,6 +2184,15 @@ impl<'a> Aspersores1<'a> {
 
                         info!("Time synced! Offset: {} seconds", offset);
 
+                        {
+                            let mut clock_trust = clock_trust.lock().unwrap();
+                            // Runs "missed" on a clock that was never set aren't caught up
+                            if *clock_trust == ClockTrust::NeverSynced {
+                                *last_seen.lock().unwrap() = local_now(offset);
+                            }
+                            *clock_trust = ClockTrust::Synced;
+                        }
+
                         let mut response = request.into_response(
                             200,
                             Some("OK"),
@@ -924,32 +2209,932 @@ impl<'a> Aspersores1<'a> {
     }
 }
 
-/// Helper function to load aspersor settings from NVS
-fn load_aspersor_settings(
-    nvs: &EspNvs<NvsDefault>,
-    name: &str,
-    default_init_time: u32,
-    default_duration: u32,
-) -> (u32, u32) {
-    // Use first 10 chars for NVS key (must match save format!)
-    let nvs_key: String = name.chars().take(10).collect();
-    let duration_key = format!("{}_d", nvs_key);
-    let init_key = format!("{}_i", nvs_key);
-
-    let init_time = nvs
-        .get_u32(&init_key)
-        .ok()
-        .flatten()
-        .unwrap_or(default_init_time);
-    let duration = nvs
-        .get_u32(&duration_key)
-        .ok()
-        .flatten()
-        .unwrap_or(default_duration);
+struct Aspersores2<'a> {
+    toberas_afuera: Aspersor<'a>,
+    rotor_frente: Aspersor<'a>,
+    costado_180: Aspersor<'a>,
+    master: Option<MasterValve<'a>>,
+    flow: Option<FlowSensor<'a>>,
+    controller: Controller,
+}
 
-    info!(
-        "Loaded {}: init_time={}, duration={} (keys: {}, {})",
-        name, init_time, duration, init_key, duration_key
-    );
-    (init_time, duration)
+impl<'a> Aspersores2<'a> {
+    /// `master_pin` drives an optional pump relay or master valve, `flow_pin` reads an
+    /// optional flow sensor for zones that water by volume
+    pub fn new_with_nvs(
+        gpio32: Gpio32,
+        gpio33: Gpio33,
+        gpio25: Gpio25,
+        master_pin: Option<AnyIOPin>,
+        flow_pin: Option<AnyInputPin>,
+        nvs: &EspNvs<NvsDefault>,
+    ) -> Self {
+        // Default values (in seconds)
+        let toberas_schedule = load_aspersor_settings(
+            nvs,
+            "toberas_afuera",
+            6 * 3600 + 15 * 60, // 6:15 AM
+            45 * 60,            // 45 minutes
+        );
+
+        let rotor_schedule = load_aspersor_settings(
+            nvs,
+            "rotor_frente",
+            7 * 3600, // 7:00 AM
+            40 * 60,  // 40 minutes
+        );
+
+        let costado_schedule = load_aspersor_settings(
+            nvs,
+            "costado_180",
+            5 * 3600, // 5:00 AM
+            75 * 60,  // 1h 15m
+        );
+
+        let toberas_afuera =
+            Aspersor::new_with_settings("toberas_afuera".to_string(), gpio32, toberas_schedule);
+        let rotor_frente =
+            Aspersor::new_with_settings("rotor_frente".to_string(), gpio33, rotor_schedule);
+        let costado_180 =
+            Aspersor::new_with_settings("costado_180".to_string(), gpio25, costado_schedule);
+
+        let controller =
+            Controller::new_with_nvs(nvs, &[&costado_180, &toberas_afuera, &rotor_frente]);
+
+        Aspersores2 {
+            toberas_afuera,
+            rotor_frente,
+            costado_180,
+            master: master_pin.map(|pin| MasterValve::new_with_nvs(pin, nvs)),
+            flow: flow_pin.map(|pin| FlowSensor::new_with_nvs(pin, nvs)),
+            controller,
+        }
+    }
+
+    // pub fn day_execution(&self) {
+    //     let is_manual_mode = *self.manual_mode.lock().unwrap();
+    //     if !is_manual_mode {
+    //         self.costado_180.start();
+    //         self.toberas_afuera.start();
+    //         self.rotor_frente.start();
+    //     }
+    // }
+
+    /// Zones in the order they are updated and listed in `/get_info`
+    fn zones(&self) -> [&Aspersor<'a>; 3] {
+        [&self.costado_180, &self.toberas_afuera, &self.rotor_frente]
+    }
+
+    /// Non-blocking: Call this every loop iteration
+    pub fn update_all(&self, time_offset: i64, nvs: &Arc<Mutex<EspNvs<NvsDefault>>>) {
+        self.controller.update_all(
+            &self.zones(),
+            self.master.as_ref(),
+            self.flow.as_ref(),
+            time_offset,
+            nvs,
+        );
+    }
+
+    pub fn register_http_handlers(
+        &self,
+        server: &mut EspHttpServer<'a>,
+        time_offset: Arc<Mutex<i64>>,
+        nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
+    ) {
+        self.controller.register_http_handlers(
+            server,
+            &self.zones(),
+            self.master.as_ref(),
+            self.flow.as_ref(),
+            time_offset,
+            nvs,
+        );
+    }
+}
+
+/// Banner for the root page and `/get_info` while automatic runs wait for `/set_time`
+fn clock_warning(clock_trust: ClockTrust) -> Option<&'static str> {
+    match clock_trust {
+        ClockTrust::NeverSynced => Some(
+            "The clock was never set. Automatic watering is paused until you tap Sync Time.",
+        ),
+        ClockTrust::Restored => Some(
+            "The clock was restored after a restart and may be behind by how long the power was off. Automatic watering is paused until you tap Sync Time.",
+        ),
+        ClockTrust::Synced => None,
+    }
+}
+
+/// Current local (UTC-3) day number and seconds from midnight, with the synced offset applied
+fn local_day_and_time(time_offset: i64) -> (i64, u32) {
+    schedule::split_local_timestamp(local_now(time_offset))
+}
+
+/// Current local time (UTC-3) in seconds since the epoch, see `schedule::local_seconds`
+fn local_now(time_offset: i64) -> i64 {
+    let adjusted = Utc::now() + TimeDelta::seconds(time_offset);
+    local_timestamp(adjusted.timestamp())
+}
+
+/// Shift a Unix timestamp to local time (UTC-3)
+fn local_timestamp(unix_timestamp: i64) -> i64 {
+    let tz = chrono::FixedOffset::west_opt(3 * 3600).unwrap();
+    unix_timestamp + i64::from(tz.local_minus_utc())
+}
+
+/// `YYYY-MM-DD HH:MM` of a local timestamp
+fn format_local(local_seconds: i64) -> String {
+    DateTime::from_timestamp(local_seconds, 0)
+        .map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// Day number (days since 1970-01-01) of a `YYYY-MM-DD` date
+fn parse_day(value: &str) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp() / 86400)
+}
+
+/// `YYYY-MM-DD` date of a day number
+fn format_day(day: i64) -> String {
+    DateTime::from_timestamp(day * 86400, 0)
+        .map(|date| date.date_naive().to_string())
+        .unwrap_or_default()
+}
+
+/// Apply the `/update_aspersor` query params on top of the current schedule
+fn apply_schedule_params(
+    uri: &str,
+    current: &ZoneSchedule,
+) -> core::result::Result<ZoneSchedule, &'static str> {
+    let mut updated = current.clone();
+
+    if let Some(value) = get_query_param(uri, "slots") {
+        // Full list, e.g. slots=21600:2700,64800:1200
+        updated.slots = schedule::parse_slots(value)
+            .filter(|slots| !slots.is_empty())
+            .ok_or("invalid slots")?;
+    } else if let (Some(init_time), Some(duration)) = (
+        get_query_param(uri, "init_time"),
+        get_query_param(uri, "duration"),
+    ) {
+        // Single pair only replaces the first slot, keeping the others
+        let first = Slot::new(
+            init_time.parse().unwrap_or(0),
+            duration.parse().unwrap_or(0),
+        );
+        match updated.slots.first_mut() {
+            Some(slot) => *slot = first,
+            None => updated.slots.push(first),
+        }
+    }
+
+    if let Some(value) = get_query_param(uri, "enabled") {
+        // Disabled zones keep their settings but are skipped by the scheduler
+        updated.enabled = value == "1" || value == "true";
+    }
+
+    if let Some(value) = get_query_param(uri, "weekdays") {
+        // Mask (Monday = 1, ..., Sunday = 64) or names, e.g. weekdays=mon,wed,fri
+        updated.weekdays = schedule::parse_weekdays(value).ok_or("invalid weekdays")?;
+    }
+
+    if let Some(value) = get_query_param(uri, "interval_days") {
+        // Every N days from interval_start (YYYY-MM-DD), 0 goes back to the weekday mask
+        let every_days: u16 = value.parse().map_err(|_| "invalid interval_days")?;
+        let anchor_day = get_query_param(uri, "interval_start")
+            .map(|start| parse_day(start).ok_or("invalid interval_start"))
+            .transpose()?
+            .or(current.interval.map(|interval| interval.anchor_day));
+
+        updated.interval = match (every_days, anchor_day) {
+            (0, _) => None,
+            (1..=schedule::MAX_INTERVAL_DAYS, Some(anchor_day)) => Some(Interval {
+                anchor_day,
+                every_days,
+            }),
+            (1..=schedule::MAX_INTERVAL_DAYS, None) => return Err("missing interval_start"),
+            _ => return Err("invalid interval_days"),
+        };
+    }
+
+    if let Some(value) = get_query_param(uri, "max_cycle") {
+        // Seconds per cycle with a soak pause (seconds) in between, 0 turns cycle-and-soak off
+        let max_cycle: u32 = value.parse().map_err(|_| "invalid max_cycle")?;
+        let soak = match get_query_param(uri, "soak") {
+            Some(soak) => soak.parse().map_err(|_| "invalid soak")?,
+            None => current.cycle_soak.map_or(0, |cycle_soak| cycle_soak.soak),
+        };
+
+        updated.cycle_soak = match max_cycle {
+            0 => None,
+            1..=schedule::SECONDS_PER_DAY if soak <= schedule::SECONDS_PER_DAY => {
+                Some(CycleSoak { max_cycle, soak })
+            }
+            _ => return Err("invalid cycle_soak"),
+        };
+    }
+
+    if let Some(value) = get_query_param(uri, "volume") {
+        // Liters per run, the slot duration becomes the cap. 0 goes back to watering by time.
+        let liters: u32 = value.parse().map_err(|_| "invalid volume")?;
+        updated.volume = match liters {
+            0 => None,
+            1..=schedule::MAX_VOLUME => Some(liters),
+            _ => return Err("invalid volume"),
+        };
+    }
+
+    Result::Ok(updated)
+}
+
+/// Watering seconds left for `zone` in `runs` from `now` on, across all its cycles
+fn remaining(runs: &[schedule::Run], zone: usize, now: i64) -> u32 {
+    runs.iter()
+        .filter(|run| run.zone == zone && run.end() > now)
+        .map(|run| (run.end() - run.start.max(now)) as u32)
+        .sum()
+}
+
+/// Apply the `/set_program` query params on top of the current program
+fn apply_program_params(
+    uri: &str,
+    current: &Program,
+    zone_names: &[String],
+) -> core::result::Result<Program, &'static str> {
+    let mut updated = current.clone();
+
+    if let Some(value) = get_query_param(uri, "enabled") {
+        updated.enabled = value == "1" || value == "true";
+    }
+
+    if let Some(value) = get_query_param(uri, "start_time") {
+        updated.start_time = value
+            .parse()
+            .ok()
+            .filter(|start_time| *start_time < schedule::SECONDS_PER_DAY)
+            .ok_or("invalid start_time")?;
+    }
+
+    if let Some(value) = get_query_param(uri, "order") {
+        // Zone names in run order, e.g. order=rotor_frente,toberas_afuera
+        updated.order = parse_program_order(value, zone_names).ok_or("invalid order")?;
+    }
+
+    Result::Ok(updated)
+}
+
+/// The `ControllerSchedule` fields `/set_controller` edits
+#[derive(Debug, Clone, Copy)]
+struct ControllerSettings {
+    max_concurrent: u8,
+    zone_gap: u32,
+    budget_percent: u16,
+    location: Location,
+    catch_up_grace: u32,
+}
+
+impl ControllerSettings {
+    fn of(schedule: &ControllerSchedule) -> Self {
+        ControllerSettings {
+            max_concurrent: schedule.max_concurrent,
+            zone_gap: schedule.zone_gap,
+            budget_percent: schedule.budget_percent,
+            location: schedule.location,
+            catch_up_grace: schedule.catch_up_grace,
+        }
+    }
+
+    /// Set these fields on `schedule`, leaving the rest as they are
+    fn apply_to(&self, schedule: &mut ControllerSchedule) {
+        schedule.max_concurrent = self.max_concurrent;
+        schedule.zone_gap = self.zone_gap;
+        schedule.budget_percent = self.budget_percent;
+        schedule.location = self.location;
+        schedule.catch_up_grace = self.catch_up_grace;
+    }
+}
+
+/// Apply the `/set_controller` query params on top of the current settings
+fn apply_controller_params(
+    uri: &str,
+    current: ControllerSettings,
+) -> core::result::Result<ControllerSettings, &'static str> {
+    let mut updated = current;
+
+    if let Some(value) = get_query_param(uri, "max_concurrent") {
+        // 0 = no limit
+        updated.max_concurrent = value.parse().ok().ok_or("invalid max_concurrent")?;
+    }
+
+    if let Some(value) = get_query_param(uri, "zone_gap") {
+        // Seconds from one zone closing to the next opening, 0 = none
+        updated.zone_gap = value
+            .parse()
+            .ok()
+            .filter(|gap| *gap <= schedule::MAX_ZONE_GAP)
+            .ok_or("invalid zone_gap")?;
+    }
+
+    if let Some(value) = get_query_param(uri, "budget_percent") {
+        // Scales every duration, e.g. 50 in winter and 150 in a heat wave
+        updated.budget_percent = value
+            .parse()
+            .ok()
+            .filter(|percent| (1..=schedule::MAX_BUDGET_PERCENT).contains(percent))
+            .ok_or("invalid budget_percent")?;
+    }
+
+    if let Some(value) = get_query_param(uri, "latitude") {
+        updated.location.latitude = value.parse().map_err(|_| "invalid latitude")?;
+    }
+    if let Some(value) = get_query_param(uri, "longitude") {
+        updated.location.longitude = value.parse().map_err(|_| "invalid longitude")?;
+    }
+    if !updated.location.is_valid() {
+        return Err("invalid location");
+    }
+
+    if let Some(value) = get_query_param(uri, "catch_up_grace") {
+        // Seconds, 0 = off. Only today's and yesterday's runs are planned, so at most a day.
+        updated.catch_up_grace = value
+            .parse()
+            .ok()
+            .filter(|grace| *grace <= schedule::SECONDS_PER_DAY)
+            .ok_or("invalid catch_up_grace")?;
+    }
+
+    Result::Ok(updated)
+}
+
+/// Apply the `/set_controller` master valve params (seconds) on top of the current timing
+fn apply_master_params(
+    uri: &str,
+    current: MasterTiming,
+) -> core::result::Result<MasterTiming, &'static str> {
+    let mut updated = current;
+
+    if let Some(value) = get_query_param(uri, "master_lead") {
+        updated.lead = value
+            .parse()
+            .ok()
+            .filter(|lead| *lead <= schedule::MAX_MASTER_LEAD)
+            .ok_or("invalid master_lead")?;
+    }
+    if let Some(value) = get_query_param(uri, "master_lag") {
+        updated.lag = value
+            .parse()
+            .ok()
+            .filter(|lag| *lag <= schedule::MAX_MASTER_LAG)
+            .ok_or("invalid master_lag")?;
+    }
+
+    Result::Ok(updated)
+}
+
+/// Flow sensor calibration from `/set_controller?pulses_per_liter=`, e.g. 7.5
+fn parse_flow_meter(value: &str) -> core::result::Result<FlowMeter, &'static str> {
+    value
+        .parse()
+        .ok()
+        .map(|pulses_per_liter| FlowMeter { pulses_per_liter })
+        .filter(|meter| meter.is_valid() && meter.pulses_per_liter <= 10_000.0)
+        .ok_or("invalid pulses_per_liter")
+}
+
+/// Zone indices for a comma separated list of zone names. Unknown or repeated names fail.
+fn parse_program_order<S: AsRef<str>>(value: &str, zone_names: &[S]) -> Option<Vec<usize>> {
+    let mut order = Vec::new();
+
+    for name in value.split(',').filter(|name| !name.is_empty()) {
+        let index = zone_names.iter().position(|zone| zone.as_ref() == name)?;
+        if order.contains(&index) {
+            return None;
+        }
+        order.push(index);
+    }
+
+    Some(order)
+}
+
+fn parse_http_uri<'a>(uri: &'a str, param: &str) -> &'a str {
+    get_query_param(uri, param).unwrap_or("")
+}
+
+/// Value of `param` in the query string (after the '?'), or `None` if it is not present
+fn get_query_param<'a>(uri: &'a str, param: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == param)
+        .map(|(_, value)| value)
+}
+
+/// **Not recomended pins: 6 - 11, 16 - 17
+struct Aspersores1<'a> {
+    microaspersores_frente: Aspersor<'a>,
+    goteros: Aspersor<'a>,
+    atras_360: Aspersor<'a>,
+    atras_pileta: Aspersor<'a>,
+    master: Option<MasterValve<'a>>,
+    flow: Option<FlowSensor<'a>>,
+    controller: Controller,
+}
+
+impl<'a> Aspersores1<'a> {
+    /// `master_pin` drives an optional pump relay or master valve, `flow_pin` reads an
+    /// optional flow sensor for zones that water by volume
+    pub fn new_with_nvs(
+        gpio32: Gpio32,
+        gpio33: Gpio33,
+        gpio25: Gpio25,
+        gpio26: Gpio26,
+        master_pin: Option<AnyIOPin>,
+        flow_pin: Option<AnyInputPin>,
+        nvs: &EspNvs<NvsDefault>,
+    ) -> Self {
+        // Default values (in seconds)
+        let micro_schedule = load_aspersor_settings(
+            nvs,
+            "micro_frente", // shortened to fit NVS 15-char key limit
+            22 * 3600,      // 22:00
+            20 * 60,        // 20 minutes
+        );
+
+        let goteros_schedule = load_aspersor_settings(
+            nvs,
+            "goteros",
+            16 * 3600, // 16:00
+            5 * 3600,  // 5 hours
+        );
+
+        let atras360_schedule = load_aspersor_settings(
+            nvs,
+            "atras_360",
+            3 * 3600 + 30 * 60, // 3:30 AM
+            90 * 60,            // 1h 30m
+        );
+
+        let pileta_schedule = load_aspersor_settings(
+            nvs,
+            "atras_pileta",
+            21 * 3600, // 21:00
+            60 * 60,   // 1 hour
+        );
+
+        let microaspersores_frente = Aspersor::new_with_settings(
+            "micro_frente".to_string(), // Use same short name as NVS key
+            gpio32,
+            micro_schedule,
+        );
+        let goteros = Aspersor::new_with_settings("goteros".to_string(), gpio33, goteros_schedule);
+        let atras_360 =
+            Aspersor::new_with_settings("atras_360".to_string(), gpio25, atras360_schedule);
+        let atras_pileta =
+            Aspersor::new_with_settings("atras_pileta".to_string(), gpio26, pileta_schedule);
+
+        let controller = Controller::new_with_nvs(
+            nvs,
+            &[&microaspersores_frente, &goteros, &atras_360, &atras_pileta],
+        );
+
+        Aspersores1 {
+            microaspersores_frente,
+            goteros,
+            atras_360,
+            atras_pileta,
+            master: master_pin.map(|pin| MasterValve::new_with_nvs(pin, nvs)),
+            flow: flow_pin.map(|pin| FlowSensor::new_with_nvs(pin, nvs)),
+            controller,
+        }
+    }
+
+    /// Zones in the order they are updated and listed in `/get_info`
+    fn zones(&self) -> [&Aspersor<'a>; 4] {
+        [
+            &self.microaspersores_frente,
+            &self.goteros,
+            &self.atras_360,
+            &self.atras_pileta,
+        ]
+    }
+
+    /// Non-blocking: Call this every loop iteration
+    pub fn update_all(&self, time_offset: i64, nvs: &Arc<Mutex<EspNvs<NvsDefault>>>) {
+        self.controller.update_all(
+            &self.zones(),
+            self.master.as_ref(),
+            self.flow.as_ref(),
+            time_offset,
+            nvs,
+        );
+    }
+
+    pub fn register_http_handlers(
+        &self,
+        server: &mut EspHttpServer<'a>,
+        time_offset: Arc<Mutex<i64>>,
+        nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
+    ) {
+        self.controller.register_http_handlers(
+            server,
+            &self.zones(),
+            self.master.as_ref(),
+            self.flow.as_ref(),
+            time_offset,
+            nvs,
+        );
+    }
+}
+
+/// Short NVS key for an aspersor setting (max 15 chars): first 10 chars of name + suffix,
+/// e.g. "toberas_af_d"
+fn nvs_key(name: &str, suffix: &str) -> String {
+    let nvs_key: String = name.chars().take(10).collect();
+    format!("{}_{}", nvs_key, suffix)
+}
+
+/// Helper function to load aspersor settings from NVS
+fn load_aspersor_settings(
+    nvs: &EspNvs<NvsDefault>,
+    name: &str,
+    default_init_time: u32,
+    default_duration: u32,
+) -> ZoneSchedule {
+    let duration_key = nvs_key(name, "d");
+    let init_key = nvs_key(name, "i");
+    let slots_key = nvs_key(name, "s");
+    let weekdays_key = nvs_key(name, "w");
+    let interval_key = nvs_key(name, "n");
+    let anchor_key = nvs_key(name, "a");
+    let cycle_key = nvs_key(name, "c");
+    let soak_key = nvs_key(name, "k");
+    let start_key = nvs_key(name, "o");
+    let enabled_key = nvs_key(name, "x");
+    let volume_key = nvs_key(name, "l");
+
+    let init_time = nvs
+        .get_u32(&init_key)
+        .ok()
+        .flatten()
+        .unwrap_or(default_init_time);
+    let duration = nvs
+        .get_u32(&duration_key)
+        .ok()
+        .flatten()
+        .unwrap_or(default_duration);
+
+    // The first slot lives in the "_i"/"_d" keys (and "_o" if it is sunrise/sunset relative),
+    // any extra slots in "_s"
+    let mut first = Slot::new(init_time, duration);
+    let mut buf = [0u8; 16];
+    if let Some(anchor) = nvs
+        .get_str(&start_key, &mut buf)
+        .ok()
+        .flatten()
+        .and_then(Anchor::parse)
+    {
+        first.anchor = anchor;
+    }
+
+    let mut slots = vec![first];
+    let mut buf = [0u8; 64];
+    if let Some(extra) = nvs
+        .get_str(&slots_key, &mut buf)
+        .ok()
+        .flatten()
+        .and_then(schedule::parse_slots)
+    {
+        slots.extend(extra);
+    }
+
+    let weekdays = nvs
+        .get_u8(&weekdays_key)
+        .ok()
+        .flatten()
+        .unwrap_or(schedule::EVERY_DAY);
+
+    // Interval of 0 days (or missing) means the weekday mask is used
+    let every_days = nvs.get_u16(&interval_key).ok().flatten().unwrap_or(0);
+    let anchor_day = nvs.get_i32(&anchor_key).ok().flatten().unwrap_or(0);
+    let interval = (every_days > 0).then_some(Interval {
+        anchor_day: i64::from(anchor_day),
+        every_days,
+    });
+
+    // Cycle of 0 seconds (or missing) means the slot runs in one go
+    let max_cycle = nvs.get_u32(&cycle_key).ok().flatten().unwrap_or(0);
+    let soak = nvs.get_u32(&soak_key).ok().flatten().unwrap_or(0);
+    let cycle_soak = (max_cycle > 0).then_some(CycleSoak { max_cycle, soak });
+
+    let enabled = nvs.get_u8(&enabled_key).ok().flatten().unwrap_or(1) != 0;
+
+    // Volume of 0 liters (or missing) means the zone waters by time
+    let volume = nvs
+        .get_u32(&volume_key)
+        .ok()
+        .flatten()
+        .filter(|liters| *liters > 0);
+
+    info!(
+        "Loaded {}: enabled={}, slots={}, weekdays={:#09b}, interval={:?}, cycle_soak={:?}, volume={:?} (keys: {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {})",
+        name,
+        enabled,
+        schedule::encode_slots(&slots),
+        weekdays,
+        interval,
+        cycle_soak,
+        volume,
+        init_key,
+        duration_key,
+        slots_key,
+        weekdays_key,
+        interval_key,
+        anchor_key,
+        cycle_key,
+        soak_key,
+        start_key,
+        enabled_key,
+        volume_key
+    );
+
+    ZoneSchedule {
+        weekdays,
+        interval,
+        cycle_soak,
+        enabled,
+        volume,
+        ..ZoneSchedule::new(slots)
+    }
+}
+
+/// Helper function to save aspersor settings to NVS (same layout as `load_aspersor_settings`)
+fn save_aspersor_settings(nvs: &mut EspNvs<NvsDefault>, name: &str, schedule: &ZoneSchedule) {
+    let Some((first, extra)) = schedule.slots.split_first() else {
+        return;
+    };
+
+    let duration_key = nvs_key(name, "d");
+    let init_key = nvs_key(name, "i");
+    let slots_key = nvs_key(name, "s");
+    let weekdays_key = nvs_key(name, "w");
+    let interval_key = nvs_key(name, "n");
+    let anchor_key = nvs_key(name, "a");
+    let cycle_key = nvs_key(name, "c");
+    let soak_key = nvs_key(name, "k");
+    let start_key = nvs_key(name, "o");
+    let enabled_key = nvs_key(name, "x");
+    let volume_key = nvs_key(name, "l");
+
+    if let Err(e) = nvs.set_u8(&enabled_key, schedule.enabled as u8) {
+        println!("NVS save error for {}: {:?}", enabled_key, e);
+    }
+    if let Err(e) = nvs.set_u32(&duration_key, first.duration) {
+        println!("NVS save error for {}: {:?}", duration_key, e);
+    }
+    if let Err(e) = nvs.set_u32(&init_key, first.init_time) {
+        println!("NVS save error for {}: {:?}", init_key, e);
+    }
+    // Empty for a fixed clock time
+    let first_anchor = first.anchor.encode().unwrap_or_default();
+    if let Err(e) = nvs.set_str(&start_key, &first_anchor) {
+        println!("NVS save error for {}: {:?}", start_key, e);
+    }
+    if let Err(e) = nvs.set_str(&slots_key, &schedule::encode_slots(extra)) {
+        println!("NVS save error for {}: {:?}", slots_key, e);
+    }
+    if let Err(e) = nvs.set_u8(&weekdays_key, schedule.weekdays) {
+        println!("NVS save error for {}: {:?}", weekdays_key, e);
+    }
+
+    let (every_days, anchor_day) = schedule.interval.map_or((0, 0), |interval| {
+        (interval.every_days, interval.anchor_day)
+    });
+    if let Err(e) = nvs.set_u16(&interval_key, every_days) {
+        println!("NVS save error for {}: {:?}", interval_key, e);
+    }
+    if let Err(e) = nvs.set_i32(&anchor_key, anchor_day as i32) {
+        println!("NVS save error for {}: {:?}", anchor_key, e);
+    }
+
+    let (max_cycle, soak) = schedule
+        .cycle_soak
+        .map_or((0, 0), |cycle_soak| (cycle_soak.max_cycle, cycle_soak.soak));
+    if let Err(e) = nvs.set_u32(&cycle_key, max_cycle) {
+        println!("NVS save error for {}: {:?}", cycle_key, e);
+    }
+    if let Err(e) = nvs.set_u32(&soak_key, soak) {
+        println!("NVS save error for {}: {:?}", soak_key, e);
+    }
+    if let Err(e) = nvs.set_u32(&volume_key, schedule.volume.unwrap_or(0)) {
+        println!("NVS save error for {}: {:?}", volume_key, e);
+    }
+}
+
+/// Save the controller-wide settings edited through `/set_controller`
+fn save_controller_settings(nvs: &EspNvs<NvsDefault>, settings: &ControllerSettings) {
+    if let Err(e) = nvs.set_u8("max_open", settings.max_concurrent) {
+        println!("NVS save error for max_open: {:?}", e);
+    }
+    if let Err(e) = nvs.set_u32("zone_gap", settings.zone_gap) {
+        println!("NVS save error for zone_gap: {:?}", e);
+    }
+    if let Err(e) = nvs.set_u16("budget", settings.budget_percent) {
+        println!("NVS save error for budget: {:?}", e);
+    }
+
+    // Millionths of a degree, NVS has no float type
+    let latitude = (settings.location.latitude * 1e6).round() as i32;
+    let longitude = (settings.location.longitude * 1e6).round() as i32;
+    if let Err(e) = nvs.set_i32("lat", latitude) {
+        println!("NVS save error for lat: {:?}", e);
+    }
+    if let Err(e) = nvs.set_i32("lon", longitude) {
+        println!("NVS save error for lon: {:?}", e);
+    }
+
+    if let Err(e) = nvs.set_u32("catchup_grace", settings.catch_up_grace) {
+        println!("NVS save error for catchup_grace: {:?}", e);
+    }
+}
+
+/// Location for sunrise/sunset slots, see `save_controller_settings` for the layout
+fn load_location(nvs: &EspNvs<NvsDefault>) -> Location {
+    match (
+        nvs.get_i32("lat").ok().flatten(),
+        nvs.get_i32("lon").ok().flatten(),
+    ) {
+        (Some(latitude), Some(longitude)) => Location {
+            latitude: f64::from(latitude) / 1e6,
+            longitude: f64::from(longitude) / 1e6,
+        },
+        _ => Location::DEFAULT,
+    }
+}
+
+/// Load a zone override: "_v" is 0 = none, 1 = on, 2 = off and "_e" the local expiry
+/// (0 = until cleared). An expired one is dropped by `update_all` once the clock is known.
+fn load_override(nvs: &EspNvs<NvsDefault>, name: &str) -> Option<Override> {
+    let mode_key = nvs_key(name, "v");
+    let until_key = nvs_key(name, "e");
+
+    let on = match nvs.get_u8(&mode_key).ok().flatten() {
+        Some(1) => true,
+        Some(2) => false,
+        _ => return None,
+    };
+    let until = nvs.get_i64(&until_key).ok().flatten().unwrap_or(0);
+
+    let zone_override = Override {
+        on,
+        until: (until > 0).then_some(until),
+    };
+    info!("Loaded override for {}: {:?}", name, zone_override);
+    Some(zone_override)
+}
+
+/// Load a zone's runtime counters, stored under "_r" (see `RuntimeLog::encode`)
+fn load_runtime(nvs: &EspNvs<NvsDefault>, name: &str) -> RuntimeLog {
+    let mut buf = [0u8; 512];
+    let runtime = nvs
+        .get_str(&nvs_key(name, "r"), &mut buf)
+        .ok()
+        .flatten()
+        .and_then(RuntimeLog::parse)
+        .unwrap_or_default();
+    info!("Loaded runtime for {}: {}", name, runtime.encode());
+    runtime
+}
+
+fn save_runtime(nvs: &mut EspNvs<NvsDefault>, name: &str, runtime: &RuntimeLog) {
+    let key = nvs_key(name, "r");
+    if let Err(e) = nvs.set_str(&key, &runtime.encode()) {
+        println!("NVS save error for {}: {:?}", key, e);
+    }
+}
+
+/// Save a zone override, same layout as `load_override`
+fn save_override(nvs: &EspNvs<NvsDefault>, name: &str, zone_override: Option<Override>) {
+    let mode_key = nvs_key(name, "v");
+    let until_key = nvs_key(name, "e");
+
+    let (mode, until) = match zone_override {
+        Some(hold) => (if hold.on { 1 } else { 2 }, hold.until.unwrap_or(0)),
+        None => (0, 0),
+    };
+    if let Err(e) = nvs.set_u8(&mode_key, mode) {
+        println!("NVS save error for {}: {:?}", mode_key, e);
+    }
+    if let Err(e) = nvs.set_i64(&until_key, until) {
+        println!("NVS save error for {}: {:?}", until_key, e);
+    }
+}
+
+/// Safety limit of a zone in seconds, `DEFAULT_MAX_ON` if never set
+fn load_max_on(nvs: &EspNvs<NvsDefault>, name: &str) -> u32 {
+    let max_on = nvs
+        .get_u32(&nvs_key(name, "m"))
+        .ok()
+        .flatten()
+        .unwrap_or(schedule::DEFAULT_MAX_ON);
+    info!("Loaded {} max_on: {} s", name, max_on);
+    max_on
+}
+
+fn save_max_on(nvs: &EspNvs<NvsDefault>, name: &str, max_on: u32) {
+    let key = nvs_key(name, "m");
+    if let Err(e) = nvs.set_u32(&key, max_on) {
+        println!("NVS save error for {}: {:?}", key, e);
+    }
+}
+
+/// Load the sequential program from NVS, defaulting to disabled and every zone in order
+fn load_program(nvs: &EspNvs<NvsDefault>, zone_names: &[&str]) -> Program {
+    let enabled = nvs.get_u8("prog_enabled").ok().flatten().unwrap_or(0) != 0;
+    let start_time = nvs.get_u32("prog_start").ok().flatten().unwrap_or(5 * 3600); // 5:00 AM
+
+    let mut buf = [0u8; 96];
+    let order = nvs
+        .get_str("prog_order", &mut buf)
+        .ok()
+        .flatten()
+        .and_then(|value| parse_program_order(value, zone_names))
+        .unwrap_or_else(|| (0..zone_names.len()).collect());
+
+    let program = Program {
+        enabled,
+        start_time,
+        order,
+    };
+    info!("Loaded program: {:?}", program);
+    program
+}
+
+/// Seasons from NVS, one string per season under `season0`, `season1`, ... (see `Season::encode`)
+fn load_seasons(nvs: &EspNvs<NvsDefault>, zone_names: &[&str]) -> Vec<Season> {
+    // Room for the NUL terminator
+    let mut buf = [0u8; schedule::MAX_SEASON_LEN + 1];
+    let seasons: Vec<Season> = (0..schedule::MAX_SEASONS)
+        .filter_map(|i| {
+            let key = format!("season{}", i);
+            let value = match nvs.get_str(&key, &mut buf) {
+                Result::Ok(value) => value?,
+                Err(e) => {
+                    println!("NVS read error for {}: {:?}", key, e);
+                    return None;
+                }
+            };
+            let season = Season::parse(value, zone_names);
+            if season.is_none() {
+                println!("Ignoring invalid season in NVS: {}", value);
+            }
+            season
+        })
+        .collect();
+
+    info!(
+        "Loaded seasons: {:?}",
+        seasons
+            .iter()
+            .map(|season| &season.name)
+            .collect::<Vec<_>>()
+    );
+    seasons
+}
+
+/// Save the seasons to NVS, removing the keys of deleted ones
+fn save_seasons(nvs: &mut EspNvs<NvsDefault>, seasons: &[Season], zone_names: &[String]) {
+    for i in 0..schedule::MAX_SEASONS {
+        let key = format!("season{}", i);
+        let result = match seasons.get(i) {
+            Some(season) => nvs.set_str(&key, &season.encode(zone_names)),
+            None => nvs.remove(&key).map(|_| ()),
+        };
+        if let Err(e) = result {
+            println!("NVS save error for {}: {:?}", key, e);
+        }
+    }
+}
+
+/// Save the sequential program to NVS, with the order stored by zone name
+fn save_program(nvs: &mut EspNvs<NvsDefault>, program: &Program, zone_names: &[String]) {
+    let order = program
+        .order
+        .iter()
+        .map(|&i| zone_names[i].as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    if let Err(e) = nvs.set_u8("prog_enabled", program.enabled as u8) {
+        println!("NVS save error for prog_enabled: {:?}", e);
+    }
+    if let Err(e) = nvs.set_u32("prog_start", program.start_time) {
+        println!("NVS save error for prog_start: {:?}", e);
+    }
+    if let Err(e) = nvs.set_str("prog_order", &order) {
+        println!("NVS save error for prog_order: {:?}", e);
+    }
 }
@@ -1,7 +1,12 @@
 // Embed the JS file at compile time
 const SYNC_TIME: &str = include_str!("html_scripts/syncTime.js");
 
-pub fn get_root_html(server_time: &str) -> String {
+/// `clock_warning` is shown on top while automatic watering waits for the clock to be synced
+pub fn get_root_html(server_time: &str, clock_warning: Option<&str>) -> String {
+    let clock_warning = clock_warning
+        .map(|warning| format!(r#"<div class="clock-warning">⚠️ {}</div>"#, warning))
+        .unwrap_or_default();
+
     let html_template = format!(
         r#"<!DOCTYPE html>
 <html>
@@ -127,11 +132,24 @@ pub fn get_root_html(server_time: &str) -> String {
       background: #3b82f6;
       color: white;
     }}
+    .clock-warning {{
+      font-size: 18px;
+      font-weight: bold;
+      color: #1a1a2e;
+      background: #f87171;
+      padding: 15px 20px;
+      border-radius: 8px;
+      margin: 15px 0;
+      max-width: 600px;
+      text-align: center;
+    }}
 </style>
 </head>
 <body>
 <h1>🌱 Control de Aspersores</h1>
 
+{clock_warning}
+
 <div class="clock-container">
   <div class="clock-display">
     <span class="clock-digit" id="clock-hours">00</span>
@@ -153,6 +171,15 @@ pub fn get_root_html(server_time: &str) -> String {
 
 <p class="note">⚠️ Manual: <span class="mode-label manual">Manual</span> → on/off → <span class="mode-label auto">Auto</span></p>
 
+<h2>Programa</h2>
+<div id="program" class="aspersor">Loading...</div>
+
+<h2>Controlador</h2>
+<div id="queue" class="aspersor">Loading...</div>
+
+<h2>Próximos riegos</h2>
+<div id="upcoming" class="aspersor">Loading...</div>
+
 <h2>Aspersores</h2>
 <div id="aspersores">Loading...</div>
 
@@ -160,6 +187,7 @@ pub fn get_root_html(server_time: &str) -> String {
     const SERVER_TIME = "{server_time}";
 "#,
         server_time = server_time,
+        clock_warning = clock_warning,
     );
 
     format!(
@@ -0,0 +1,154 @@
+//! Per-zone runtime accounting: how long a zone was actually on, in daily buckets.
+
+use super::month_and_day;
+
+/// Days of history kept, enough for a whole calendar month
+pub const RUNTIME_DAYS: usize = 31;
+
+/// Seconds a zone was on per local day number, oldest first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuntimeLog {
+    days: Vec<(i64, u32)>,
+    /// Milliseconds not yet counted in a bucket, so short ticks add up
+    carry_ms: u32,
+}
+
+impl RuntimeLog {
+    /// Count `millis` of on-time on `day`, dropping days that fall out of the history
+    pub fn add(&mut self, day: i64, millis: u32) {
+        let total = self.carry_ms + millis;
+        self.carry_ms = total % 1000;
+        let seconds = total / 1000;
+        if seconds == 0 {
+            return;
+        }
+
+        match self.days.binary_search_by_key(&day, |&(bucket, _)| bucket) {
+            Ok(i) => self.days[i].1 = self.days[i].1.saturating_add(seconds),
+            Err(i) => self.days.insert(i, (day, seconds)),
+        }
+
+        self.days
+            .retain(|&(bucket, _)| bucket > day - RUNTIME_DAYS as i64);
+        // The clock may have been set back, never keep more than the history length
+        let excess = self.days.len().saturating_sub(RUNTIME_DAYS);
+        self.days.drain(..excess);
+    }
+
+    /// Daily buckets, oldest first
+    pub fn days(&self) -> &[(i64, u32)] {
+        &self.days
+    }
+
+    pub fn on_day(&self, day: i64) -> u32 {
+        self.total(|bucket| bucket == day)
+    }
+
+    /// Total of the `days` days ending on `today`, e.g. 7 for the last week
+    pub fn last_days(&self, today: i64, days: i64) -> u32 {
+        self.total(|bucket| bucket > today - days && bucket <= today)
+    }
+
+    /// Total of the calendar month `today` falls in, up to `today`
+    pub fn month(&self, today: i64) -> u32 {
+        let (_, day_of_month) = month_and_day(today);
+        let first = today - i64::from(day_of_month) + 1;
+        self.total(|bucket| bucket >= first && bucket <= today)
+    }
+
+    fn total(&self, include: impl Fn(i64) -> bool) -> u32 {
+        self.days
+            .iter()
+            .filter(|&&(bucket, _)| include(bucket))
+            .map(|&(_, seconds)| seconds)
+            .sum()
+    }
+
+    /// `day:seconds` pairs separated by commas, for NVS
+    pub fn encode(&self) -> String {
+        self.days
+            .iter()
+            .map(|(day, seconds)| format!("{}:{}", day, seconds))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Parse the format written by [`RuntimeLog::encode`]
+    pub fn parse(value: &str) -> Option<RuntimeLog> {
+        let mut days = value
+            .split(',')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (day, seconds) = pair.split_once(':')?;
+                Some((day.parse().ok()?, seconds.parse().ok()?))
+            })
+            .collect::<Option<Vec<(i64, u32)>>>()?;
+        days.sort_unstable();
+        days.dedup_by_key(|&mut (day, _)| day);
+        if days.len() > RUNTIME_DAYS {
+            return None;
+        }
+
+        Some(RuntimeLog { days, carry_ms: 0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01, a Monday
+    const JAN_1: i64 = 19723;
+
+    #[test]
+    fn adds_up_ticks_into_daily_buckets() {
+        let mut log = RuntimeLog::default();
+        for _ in 0..100 {
+            log.add(JAN_1, 1020);
+        }
+        log.add(JAN_1 + 1, 60_000);
+
+        assert_eq!(log.on_day(JAN_1), 102);
+        assert_eq!(log.on_day(JAN_1 + 1), 60);
+        assert_eq!(log.on_day(JAN_1 + 2), 0);
+    }
+
+    #[test]
+    fn week_and_month_totals() {
+        let mut log = RuntimeLog::default();
+        // 2023-12-28 to 2024-01-10, ten minutes a day
+        for day in JAN_1 - 4..JAN_1 + 10 {
+            log.add(day, 600_000);
+        }
+
+        let today = JAN_1 + 9;
+        assert_eq!(log.last_days(today, 1), 600);
+        assert_eq!(log.last_days(today, 7), 7 * 600);
+        // January only, December doesn't count
+        assert_eq!(log.month(today), 10 * 600);
+        assert_eq!(log.month(JAN_1 - 1), 4 * 600);
+    }
+
+    #[test]
+    fn keeps_a_month_of_history() {
+        let mut log = RuntimeLog::default();
+        for day in JAN_1..JAN_1 + 40 {
+            log.add(day, 1000);
+        }
+
+        assert_eq!(log.days().len(), RUNTIME_DAYS);
+        assert_eq!(log.days()[0].0, JAN_1 + 40 - RUNTIME_DAYS as i64);
+    }
+
+    #[test]
+    fn round_trips_through_nvs_format() {
+        let mut log = RuntimeLog::default();
+        log.add(JAN_1, 3_600_000);
+        log.add(JAN_1 + 2, 90_000);
+
+        assert_eq!(log.encode(), "19723:3600,19725:90");
+        assert_eq!(RuntimeLog::parse(&log.encode()), Some(log));
+        assert_eq!(RuntimeLog::parse(""), Some(RuntimeLog::default()));
+        assert_eq!(RuntimeLog::parse("19723"), None);
+    }
+}
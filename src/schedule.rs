@@ -0,0 +1,2402 @@
+//! Pure scheduling logic, kept free of ESP-IDF types so it can be unit tested on the host:
+//! `rustc --edition 2021 --test src/schedule.rs -o /tmp/schedule && /tmp/schedule`
+
+// Submodules are included by path rather than from `main.rs`, so the command above runs their
+// tests too
+#[path = "solar.rs"]
+pub mod solar;
+
+#[path = "runtime.rs"]
+pub mod runtime;
+
+#[path = "flow.rs"]
+pub mod flow;
+
+use std::time::{Duration, Instant};
+
+use solar::SunTimes;
+
+pub const SECONDS_PER_DAY: u32 = 24 * 3600;
+
+/// Local time zone (UTC-3), in seconds east of UTC
+pub const LOCAL_UTC_OFFSET: i32 = -3 * 3600;
+
+/// Weekday mask with every day set. Bit 0 is Monday ... bit 6 is Sunday.
+pub const EVERY_DAY: u8 = 0x7F;
+
+const WEEKDAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Split a local timestamp (seconds since the Unix epoch, already shifted to local time)
+/// into a day number and seconds from midnight.
+pub fn split_local_timestamp(local_timestamp: i64) -> (i64, u32) {
+    let seconds_per_day = i64::from(SECONDS_PER_DAY);
+    (
+        local_timestamp.div_euclid(seconds_per_day),
+        local_timestamp.rem_euclid(seconds_per_day) as u32,
+    )
+}
+
+/// Weekday of a day number from [`split_local_timestamp`], 0 = Monday ... 6 = Sunday
+pub fn weekday(day: i64) -> u8 {
+    // 1970-01-01 was a Thursday
+    (day + 3).rem_euclid(7) as u8
+}
+
+/// Month (1-12) and day of the month (1-31) of a day number
+pub fn month_and_day(day: i64) -> (u32, u32) {
+    // Civil-from-days, with years starting on March 1st so leap days come last
+    let z = day + 719_468;
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (month as u32, day_of_month as u32)
+}
+
+/// Parse a weekday mask, either as a number (`"21"`) or as names (`"mon,wed,fri"`)
+pub fn parse_weekdays(value: &str) -> Option<u8> {
+    if let Ok(mask) = value.parse::<u8>() {
+        return (mask <= EVERY_DAY).then_some(mask);
+    }
+
+    value.split(',').try_fold(0u8, |mask, name| {
+        let name = name.trim().to_ascii_lowercase();
+        let index = WEEKDAY_NAMES.iter().position(|day| name.starts_with(day))?;
+        Some(mask | 1 << index)
+    })
+}
+
+/// Maximum number of start slots a zone can hold (keeps the NVS string short).
+pub const MAX_SLOTS: usize = 4;
+
+/// What a slot's start is relative to. Solar offsets are in seconds, negative = before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    /// `init_time` is a fixed time of day
+    Clock,
+    Sunrise(i32),
+    Sunset(i32),
+}
+
+/// Largest offset from sunrise or sunset, either way
+pub const MAX_SOLAR_OFFSET: i32 = 12 * 3600;
+
+impl Anchor {
+    /// `sr-1800` / `ss+600` form used in slot strings, `None` for [`Anchor::Clock`]
+    pub fn encode(&self) -> Option<String> {
+        match self {
+            Anchor::Clock => None,
+            Anchor::Sunrise(offset) => Some(format!("sr{:+}", offset)),
+            Anchor::Sunset(offset) => Some(format!("ss{:+}", offset)),
+        }
+    }
+
+    /// Parse the format written by [`Anchor::encode`], the offset may be left out (`sr`)
+    pub fn parse(value: &str) -> Option<Anchor> {
+        let (kind, offset) = (value.get(..2)?, value.get(2..)?);
+        let offset = match offset {
+            "" => 0,
+            offset => offset.parse().ok()?,
+        };
+        if !(-MAX_SOLAR_OFFSET..=MAX_SOLAR_OFFSET).contains(&offset) {
+            return None;
+        }
+
+        match kind {
+            "sr" => Some(Anchor::Sunrise(offset)),
+            "ss" => Some(Anchor::Sunset(offset)),
+            _ => None,
+        }
+    }
+}
+
+/// One watering window: start in seconds from midnight plus its duration in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    /// Start of a clock slot, ignored for solar ones until they are resolved
+    pub init_time: u32,
+    pub duration: u32,
+    pub anchor: Anchor,
+}
+
+impl Slot {
+    pub fn new(init_time: u32, duration: u32) -> Self {
+        Slot {
+            init_time,
+            duration,
+            anchor: Anchor::Clock,
+        }
+    }
+
+    /// Slot starting relative to sunrise or sunset
+    pub fn solar(anchor: Anchor, duration: u32) -> Self {
+        Slot {
+            anchor,
+            ..Slot::new(0, duration)
+        }
+    }
+
+    /// Same slot with a fixed start for a day with the given sun times. Solar slots are
+    /// dropped when the sun doesn't rise or set that day.
+    pub fn resolve(&self, sun: Option<SunTimes>) -> Option<Slot> {
+        let (base, offset) = match self.anchor {
+            Anchor::Clock => return Some(*self),
+            Anchor::Sunrise(offset) => (sun?.sunrise, offset),
+            Anchor::Sunset(offset) => (sun?.sunset, offset),
+        };
+        let init_time =
+            (i64::from(base) + i64::from(offset)).rem_euclid(i64::from(SECONDS_PER_DAY));
+        Some(Slot::new(init_time as u32, self.duration))
+    }
+}
+
+/// Longest supported "every N days" interval
+pub const MAX_INTERVAL_DAYS: u16 = 365;
+
+/// Run every `every_days` days, counting from `anchor_day` (a day number)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub anchor_day: i64,
+    pub every_days: u16,
+}
+
+impl Interval {
+    pub fn runs_on(&self, day: i64) -> bool {
+        day >= self.anchor_day && (day - self.anchor_day) % i64::from(self.every_days.max(1)) == 0
+    }
+
+    /// First qualifying day on or after `day`
+    pub fn next_day(&self, day: i64) -> i64 {
+        if day <= self.anchor_day {
+            return self.anchor_day;
+        }
+        let every = i64::from(self.every_days.max(1));
+        day + (every - (day - self.anchor_day) % every) % every
+    }
+}
+
+/// Split long runs into cycles with soak gaps in between, so the water has time to sink in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleSoak {
+    /// Longest a single cycle may run, in seconds
+    pub max_cycle: u32,
+    /// Pause between cycles, in seconds
+    pub soak: u32,
+}
+
+impl CycleSoak {
+    /// Lengths of the cycles for a `duration` run, as even as possible and adding up to it
+    pub fn cycles(&self, duration: u32) -> Vec<u32> {
+        let count = duration.div_ceil(self.max_cycle.max(1)).max(1);
+        (0..count)
+            .map(|i| duration / count + u32::from(i < duration % count))
+            .collect()
+    }
+}
+
+/// Everything that decides when a single zone runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneSchedule {
+    pub slots: Vec<Slot>,
+    /// Days of the week the slots start on, see [`EVERY_DAY`]
+    pub weekdays: u8,
+    /// When set, replaces the weekday mask with an "every N days" rule
+    pub interval: Option<Interval>,
+    /// When set, runs longer than one cycle are split with soak gaps
+    pub cycle_soak: Option<CycleSoak>,
+    /// Disabled zones keep their settings but never start a run
+    pub enabled: bool,
+    /// Liters per run when watering by volume (needs a flow sensor). The slot duration is
+    /// then only a cap in case the water never gets there.
+    pub volume: Option<u32>,
+}
+
+/// Largest volume per run accepted, in liters
+pub const MAX_VOLUME: u32 = 100_000;
+
+impl ZoneSchedule {
+    pub fn new(slots: Vec<Slot>) -> Self {
+        ZoneSchedule {
+            slots,
+            weekdays: EVERY_DAY,
+            interval: None,
+            cycle_soak: None,
+            enabled: true,
+            volume: None,
+        }
+    }
+
+    /// True if a run may start on `day`
+    pub fn runs_on(&self, day: i64) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        match self.interval {
+            Some(interval) => interval.runs_on(day),
+            None => self.weekdays & (1 << weekday(day)) != 0,
+        }
+    }
+
+    /// First day on or after `day` a run may start on, `None` if the zone never runs
+    pub fn next_run_day(&self, day: i64) -> Option<i64> {
+        if !self.enabled {
+            return None;
+        }
+
+        match self.interval {
+            Some(interval) => Some(interval.next_day(day)),
+            None => (day..day + 7).find(|&d| self.runs_on(d)),
+        }
+    }
+
+    /// Copy with every solar slot turned into a clock slot for a day with `sun` times
+    pub fn resolved(&self, sun: Option<SunTimes>) -> ZoneSchedule {
+        ZoneSchedule {
+            slots: self
+                .slots
+                .iter()
+                .filter_map(|slot| slot.resolve(sun))
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Runs of `zone` (its index in the controller) for the slots starting on `day`.
+    /// Solar slots must be [resolved](ZoneSchedule::resolved) first.
+    ///
+    /// A window that crosses midnight belongs to the day it started on, so a Monday 23:00 →
+    /// 01:00 slot keeps running early on Tuesday even if Tuesday is not a watering day.
+    pub fn runs(&self, zone: usize, day: i64) -> Vec<Run> {
+        if !self.runs_on(day) {
+            return Vec::new();
+        }
+
+        self.slots
+            .iter()
+            .filter(|slot| slot.duration > 0)
+            .flat_map(|slot| {
+                self.cycles(
+                    zone,
+                    local_seconds(day, slot.init_time % SECONDS_PER_DAY),
+                    slot.duration.min(SECONDS_PER_DAY),
+                )
+            })
+            .collect()
+    }
+
+    /// Runs for `duration` seconds of watering from `start`, one per cycle if cycle-and-soak
+    /// is set. The last one ends the watering; soak gaps are not counted in `duration`.
+    pub fn cycles(&self, zone: usize, start: i64, duration: u32) -> Vec<Run> {
+        let volume_ml = self.volume.map(|liters| u64::from(liters) * 1000);
+        let Some(cycle_soak) = self.cycle_soak else {
+            let mut run = Run::new(zone, start, duration);
+            run.volume_ml = volume_ml.map(|ml| ml as u32);
+            return vec![run];
+        };
+
+        // The volume is split like the duration, each cycle gets its share
+        let mut next_start = start;
+        cycle_soak
+            .cycles(duration)
+            .into_iter()
+            .enumerate()
+            .map(|(i, cycle)| {
+                let mut run = Run::new(zone, next_start, cycle);
+                run.volume_ml =
+                    volume_ml.map(|ml| (ml * u64::from(cycle) / u64::from(duration.max(1))) as u32);
+                if i > 0 {
+                    run.soak = cycle_soak.soak;
+                }
+                next_start = run.end() + i64::from(cycle_soak.soak);
+                run
+            })
+            .collect()
+    }
+}
+
+/// Manual hold on a single zone, on top of whatever the schedule says
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Override {
+    /// `true` forces the zone on, `false` holds it off
+    pub on: bool,
+    /// Local seconds when the zone goes back to its schedule, `None` = until cleared
+    pub until: Option<i64>,
+}
+
+impl Override {
+    pub fn is_active(&self, now: i64) -> bool {
+        match self.until {
+            Some(until) => now < until,
+            None => true,
+        }
+    }
+
+    /// State the zone should be in, given what the schedule wants at `now`
+    pub fn apply(&self, scheduled: bool, now: i64) -> bool {
+        if self.is_active(now) {
+            self.on
+        } else {
+            scheduled
+        }
+    }
+}
+
+/// Longest lead and lag accepted for a master valve, in seconds
+pub const MAX_MASTER_LEAD: u32 = 60;
+pub const MAX_MASTER_LAG: u32 = 600;
+
+/// Timings of a master valve or pump relay that feeds every zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MasterTiming {
+    /// Seconds the master is on before a zone opens
+    pub lead: u32,
+    /// Seconds the master stays on after the last zone closes
+    pub lag: u32,
+}
+
+impl MasterTiming {
+    /// Whether the master should be on: while a zone wants water, from `lead` seconds before the
+    /// next run starts, for `lag` seconds after a zone was last open, and for at least `lead`
+    /// seconds once on, so a zone waiting for it can open
+    pub fn should_be_on(
+        &self,
+        zone_wanted: bool,
+        until_next_run: Option<i64>,
+        since_zone_open: Option<u64>,
+        on_for: Option<u64>,
+    ) -> bool {
+        zone_wanted
+            || until_next_run.is_some_and(|left| left <= i64::from(self.lead))
+            || since_zone_open.is_some_and(|elapsed| elapsed < u64::from(self.lag))
+            || on_for.is_some_and(|on_for| on_for < u64::from(self.lead))
+    }
+
+    /// Whether a closed zone may open, once the master has been on for `lead` seconds
+    pub fn may_open(&self, on_for: Option<u64>) -> bool {
+        on_for.is_some_and(|on_for| on_for >= u64::from(self.lead))
+    }
+}
+
+/// When manual mode goes back to auto (local seconds), `None` if it is off or never times out
+pub fn manual_mode_deadline(is_manual_mode: bool, since: i64, timeout_minutes: u32) -> Option<i64> {
+    (is_manual_mode && timeout_minutes > 0 && since > 0)
+        .then_some(since + i64::from(timeout_minutes) * 60)
+}
+
+/// The deadline manual mode passed at `now`, if it did. A clock jumping forward past it
+/// counts, e.g. the first sync after a restart.
+pub fn manual_mode_expired(
+    is_manual_mode: bool,
+    since: i64,
+    timeout_minutes: u32,
+    now: i64,
+) -> Option<i64> {
+    manual_mode_deadline(is_manual_mode, since, timeout_minutes).filter(|deadline| now >= *deadline)
+}
+
+/// Longest timed manual run accepted by `/run`
+pub const MAX_MANUAL_RUN_MINUTES: u64 = 12 * 60;
+
+/// Length of a timed manual run from `/run?minutes=`, `Duration::ZERO` to stop the one going
+pub fn manual_run_duration(minutes: &str) -> Option<Duration> {
+    minutes
+        .parse::<u64>()
+        .ok()
+        .filter(|minutes| *minutes <= MAX_MANUAL_RUN_MINUTES)
+        .map(|minutes| Duration::from_secs(minutes * 60))
+}
+
+/// A timed manual run (`/run`). Monotonic, so it doesn't depend on the synced clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ManualRun {
+    until: Option<Instant>,
+}
+
+impl ManualRun {
+    /// Run for `duration` from `now`, replacing any run in progress
+    pub fn start(&mut self, now: Instant, duration: Duration) {
+        self.until = Some(now + duration);
+    }
+
+    /// Stop the run, e.g. the zone was toggled by hand
+    pub fn cancel(&mut self) {
+        self.until = None;
+    }
+
+    /// Time left at `now`, `None` if there is no run or its time is up
+    pub fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.until?.checked_duration_since(now)
+    }
+
+    /// Clear a run whose time is up at `now`. Returns true if one just ended.
+    pub fn finish(&mut self, now: Instant) -> bool {
+        match self.until {
+            Some(until) if now >= until => {
+                self.until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Default longest time a zone may stay open in one go, above the longest default slot (5h)
+pub const DEFAULT_MAX_ON: u32 = 6 * 3600;
+/// Shortest limit accepted, in seconds
+pub const MIN_MAX_ON: u32 = 60;
+
+/// Hard limit on how long a valve stays open in one go, whatever opened it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SafetyCutoff {
+    /// Seconds
+    pub max_on: u32,
+    /// The limit cut the valve off. It stays closed until nothing asks for it anymore.
+    pub tripped: bool,
+}
+
+impl SafetyCutoff {
+    pub fn new(max_on: u32) -> Self {
+        SafetyCutoff {
+            max_on,
+            tripped: false,
+        }
+    }
+
+    /// Whether the valve may be open when something `wants_on` it and it has been on for
+    /// `on_for` seconds (`None` while closed). Trips once `max_on` is reached.
+    pub fn allow(&mut self, wants_on: bool, on_for: Option<u64>) -> bool {
+        if !wants_on {
+            self.tripped = false;
+            return false;
+        }
+        if on_for.is_some_and(|on_for| on_for >= u64::from(self.max_on)) {
+            self.tripped = true;
+        }
+        !self.tripped
+    }
+
+    /// A new request to open by hand (`/toggle`, `/run`) may reopen a zone the cutoff closed
+    pub fn rearm(&mut self) {
+        self.tripped = false;
+    }
+
+    /// Whether a run of `seconds` finishes before the limit cuts it off
+    pub fn covers(&self, seconds: u64) -> bool {
+        seconds <= u64::from(self.max_on)
+    }
+}
+
+/// Seconds since the Unix epoch in local time, the inverse of [`split_local_timestamp`]
+pub fn local_seconds(day: i64, time: u32) -> i64 {
+    day * i64::from(SECONDS_PER_DAY) + i64::from(time)
+}
+
+/// One zone run, with times in local seconds (see [`local_seconds`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Run {
+    /// Index of the zone in the controller
+    pub zone: usize,
+    /// When the schedule wanted the run to start
+    pub requested_start: i64,
+    /// When it actually starts, later than requested if it had to wait in the queue
+    pub start: i64,
+    pub duration: u32,
+    /// Minimum pause since the previous run of the same zone (the soak before this cycle)
+    pub soak: u32,
+    /// Water to deliver, the valve closes once it got there even if `duration` isn't over
+    pub volume_ml: Option<u32>,
+}
+
+impl Run {
+    pub fn new(zone: usize, start: i64, duration: u32) -> Self {
+        Run {
+            zone,
+            requested_start: start,
+            start,
+            duration,
+            soak: 0,
+            volume_ml: None,
+        }
+    }
+
+    pub fn end(&self) -> i64 {
+        self.start + i64::from(self.duration)
+    }
+
+    pub fn is_active(&self, now: i64) -> bool {
+        self.start <= now && now < self.end()
+    }
+
+    /// Should have started already but is waiting for a free valve slot
+    pub fn is_queued(&self, now: i64) -> bool {
+        self.requested_start <= now && now < self.start
+    }
+}
+
+/// Longest gap accepted between one zone closing and the next opening, in seconds
+pub const MAX_ZONE_GAP: u32 = 600;
+
+/// Delay runs so that no more than `max_concurrent` overlap (0 means no limit), and so that no
+/// zone opens less than `zone_gap` seconds after another one closed (0 means no gap).
+///
+/// Runs are served first come, first served by requested start. A delayed run keeps its full
+/// duration, and a zone is never started while a previous run of its own is still going or
+/// before its soak time is over.
+pub fn limit_concurrency(mut runs: Vec<Run>, max_concurrent: u8, zone_gap: u32) -> Vec<Run> {
+    runs.sort_by_key(|run| (run.requested_start, run.zone));
+    if max_concurrent == 0 && zone_gap == 0 {
+        return runs;
+    }
+
+    let max_concurrent = usize::from(max_concurrent);
+    let zone_gap = i64::from(zone_gap);
+    let mut placed: Vec<Run> = Vec::with_capacity(runs.len());
+
+    for mut run in runs {
+        // Never start before an earlier queued run, nor while the same zone is running or soaking
+        let previous_start = placed.last().map_or(run.start, |last| last.start);
+        let own_end = placed
+            .iter()
+            .filter(|other| other.zone == run.zone)
+            .map(|other| other.end() + i64::from(run.soak))
+            .max()
+            .unwrap_or(run.start);
+        run.start = run.start.max(previous_start).max(own_end);
+
+        // Each wait can run into the other, so repeat until neither moves the start
+        loop {
+            let start = run.start;
+
+            // Every placed run starts at or before this one, so waiting until enough of them
+            // have ended is enough to keep the limit for the whole run
+            let mut open_ends: Vec<i64> = placed
+                .iter()
+                .map(Run::end)
+                .filter(|&end| end > run.start)
+                .collect();
+            if max_concurrent > 0 && open_ends.len() >= max_concurrent {
+                open_ends.sort_unstable();
+                run.start = open_ends[open_ends.len() - max_concurrent];
+            }
+
+            // Switching one valve off and another on at once causes water hammer
+            let closing = placed
+                .iter()
+                .filter(|other| other.zone != run.zone)
+                .map(Run::end)
+                .filter(|&end| end <= run.start && run.start < end + zone_gap)
+                .max();
+            if let Some(end) = closing {
+                run.start = end + zone_gap;
+            }
+
+            if run.start == start {
+                break;
+            }
+        }
+
+        placed.push(run);
+    }
+
+    placed
+}
+
+/// Sequential program: zones run one after another, in `order`, from a single start time.
+///
+/// Each zone runs for the duration of its first slot and only on the days its own weekday
+/// mask or interval allows. Zones missing from `order` don't run while the program is enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub enabled: bool,
+    /// Seconds from midnight the first zone starts at
+    pub start_time: u32,
+    /// Zone indices in run order
+    pub order: Vec<usize>,
+}
+
+impl Program {
+    /// Runs of the program started on `day`, `zone_gap` seconds apart. Zones that don't run on
+    /// `day` are skipped and the following ones move up. Anything starting past 24h from the
+    /// program start is dropped.
+    pub fn runs(&self, day: i64, zones: &[ZoneSchedule], zone_gap: u32) -> Vec<Run> {
+        let mut offset = 0;
+        let mut runs = Vec::new();
+
+        for &index in &self.order {
+            let Some(zone) = zones.get(index) else {
+                continue;
+            };
+            let duration = zone.slots.first().map_or(0, |slot| slot.duration);
+            if duration == 0 || !zone.runs_on(day) || offset >= SECONDS_PER_DAY {
+                continue;
+            }
+
+            let duration = duration.min(SECONDS_PER_DAY - offset);
+            let start = local_seconds(day, self.start_time % SECONDS_PER_DAY) + i64::from(offset);
+            let cycles = zone.cycles(index, start, duration);
+            // The next zone waits for the last cycle, soak gaps included
+            let end = cycles.last().map_or(start, Run::end);
+            offset += (end - start) as u32 + zone_gap;
+            runs.extend(cycles);
+        }
+
+        runs
+    }
+}
+
+/// Highest water budget accepted, as a percentage of the stored durations
+pub const MAX_BUDGET_PERCENT: u16 = 200;
+
+/// Maximum number of watering restrictions (keeps the NVS string short)
+pub const MAX_RESTRICTIONS: usize = 6;
+
+/// What a watering restriction forbids
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// Only water on odd dates (1st, 3rd, ...)
+    OddDays,
+    /// Only water on even dates
+    EvenDays,
+    /// No watering from `start` to `end` (seconds from midnight, may cross midnight)
+    ForbiddenHours { start: u32, end: u32 },
+}
+
+/// A municipal watering rule, optionally only valid part of the year
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Restriction {
+    pub rule: Rule,
+    /// Yearly validity as `MMDD` from/to, inclusive, e.g. `(1201, 301)` for the summer.
+    /// `None` applies all year round.
+    pub valid: Option<(u16, u16)>,
+}
+
+impl Restriction {
+    /// True if this rule forbids watering at `now` (local seconds)
+    pub fn forbids(&self, now: i64) -> bool {
+        let (day, time) = split_local_timestamp(now);
+        let (_, day_of_month) = month_and_day(day);
+
+        if self.valid.is_some_and(|valid| !in_date_range(day, valid)) {
+            return false;
+        }
+
+        match self.rule {
+            Rule::OddDays => day_of_month % 2 == 0,
+            Rule::EvenDays => day_of_month % 2 == 1,
+            Rule::ForbiddenHours { start, end } if start <= end => start <= time && time < end,
+            Rule::ForbiddenHours { start, end } => time >= start || time < end,
+        }
+    }
+
+    /// `odd`, `even` or `no:start-end`, plus `@MMDD-MMDD` when limited to part of the year
+    pub fn encode(&self) -> String {
+        let rule = match self.rule {
+            Rule::OddDays => "odd".to_string(),
+            Rule::EvenDays => "even".to_string(),
+            Rule::ForbiddenHours { start, end } => format!("no:{}-{}", start, end),
+        };
+        match self.valid {
+            Some(valid) => format!("{}@{}", rule, encode_date_range(valid)),
+            None => rule,
+        }
+    }
+
+    /// Parse the format written by [`Restriction::encode`]
+    pub fn parse(value: &str) -> Option<Restriction> {
+        let (rule, valid) = match value.trim().split_once('@') {
+            Some((rule, valid)) => (rule, Some(valid)),
+            None => (value.trim(), None),
+        };
+
+        let rule = match rule {
+            "odd" => Rule::OddDays,
+            "even" => Rule::EvenDays,
+            _ => {
+                let (start, end) = rule.strip_prefix("no:")?.split_once('-')?;
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                if start >= SECONDS_PER_DAY || end > SECONDS_PER_DAY {
+                    return None;
+                }
+                Rule::ForbiddenHours { start, end }
+            }
+        };
+
+        let valid = match valid {
+            Some(valid) => Some(parse_date_range(valid)?),
+            None => None,
+        };
+
+        Some(Restriction { rule, valid })
+    }
+}
+
+/// `MMDD` with a real month and day, e.g. `"0301"`
+fn parse_month_day(value: &str) -> Option<u16> {
+    let date: u16 = value.parse().ok()?;
+    let (month, day) = (date / 100, date % 100);
+    ((1..=12).contains(&month) && (1..=31).contains(&day)).then_some(date)
+}
+
+/// True if `day` falls in the yearly `MMDD` range `(from, to)`, both inclusive. The range
+/// wraps around the new year when `from > to`.
+fn in_date_range(day: i64, (from, to): (u16, u16)) -> bool {
+    let (month, day_of_month) = month_and_day(day);
+    let date = (month * 100 + day_of_month) as u16;
+    if from <= to {
+        (from..=to).contains(&date)
+    } else {
+        date >= from || date <= to
+    }
+}
+
+/// `MMDD-MMDD`
+fn encode_date_range((from, to): (u16, u16)) -> String {
+    format!("{:04}-{:04}", from, to)
+}
+
+/// Parse the format written by [`encode_date_range`]
+fn parse_date_range(value: &str) -> Option<(u16, u16)> {
+    let (from, to) = value.split_once('-')?;
+    Some((parse_month_day(from)?, parse_month_day(to)?))
+}
+
+/// Maximum number of seasons, each one is a separate NVS string
+pub const MAX_SEASONS: usize = 4;
+
+/// Longest season name
+pub const MAX_SEASON_NAME: usize = 12;
+
+/// Longest encoded season, see [`Season::encode`]. Sizes the NVS read buffer.
+pub const MAX_SEASON_LEN: usize = 512;
+
+/// A named schedule set for part of the year, e.g. summer start times
+#[derive(Debug, Clone, PartialEq)]
+pub struct Season {
+    pub name: String,
+    /// Yearly validity as `MMDD` from/to, inclusive, may wrap around the new year
+    pub valid: (u16, u16),
+    /// Slots replacing each zone's own, by zone index. `None` keeps the zone's slots, an empty
+    /// list keeps the zone off for the season.
+    pub slots: Vec<Option<Vec<Slot>>>,
+    /// Start time of the sequential program for the season, `None` keeps the program's
+    pub program_start: Option<u32>,
+}
+
+impl Season {
+    pub fn covers(&self, day: i64) -> bool {
+        in_date_range(day, self.valid)
+    }
+
+    /// Copy of `zones` with the season's slots in place of their own
+    pub fn apply(&self, zones: &[ZoneSchedule]) -> Vec<ZoneSchedule> {
+        zones
+            .iter()
+            .enumerate()
+            .map(|(index, zone)| match self.slots.get(index) {
+                Some(Some(slots)) => ZoneSchedule {
+                    slots: slots.clone(),
+                    ..zone.clone()
+                },
+                _ => zone.clone(),
+            })
+            .collect()
+    }
+
+    /// `name@MMDD-MMDD`, then `;program=start` and `;zone=slots` (see [`encode_slots`]) for
+    /// each zone the season changes
+    pub fn encode<S: AsRef<str>>(&self, zone_names: &[S]) -> String {
+        let mut encoded = format!("{}@{}", self.name, encode_date_range(self.valid));
+        if let Some(start) = self.program_start {
+            encoded.push_str(&format!(";program={}", start));
+        }
+        for (name, slots) in zone_names.iter().zip(&self.slots) {
+            if let Some(slots) = slots {
+                encoded.push_str(&format!(";{}={}", name.as_ref(), encode_slots(slots)));
+            }
+        }
+        encoded
+    }
+
+    /// Parse the format written by [`Season::encode`]. Unknown zones fail.
+    pub fn parse<S: AsRef<str>>(value: &str, zone_names: &[S]) -> Option<Season> {
+        let mut parts = value.trim().split(';');
+        let (name, valid) = parts.next()?.split_once('@')?;
+        if !is_valid_season_name(name) {
+            return None;
+        }
+
+        let mut season = Season {
+            name: name.to_string(),
+            valid: parse_date_range(valid)?,
+            slots: vec![None; zone_names.len()],
+            program_start: None,
+        };
+        for part in parts.filter(|part| !part.is_empty()) {
+            let (key, value) = part.split_once('=')?;
+            if key == "program" {
+                season.program_start = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&start| start < SECONDS_PER_DAY)?,
+                );
+            } else {
+                let index = zone_names.iter().position(|zone| zone.as_ref() == key)?;
+                season.slots[index] = Some(parse_slots(value)?);
+            }
+        }
+
+        Some(season)
+    }
+}
+
+/// Letters, digits, `_` and `-`, at most [`MAX_SEASON_NAME`] long
+pub fn is_valid_season_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_SEASON_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Restrictions separated by commas, see [`Restriction::encode`]
+pub fn encode_restrictions(restrictions: &[Restriction]) -> String {
+    restrictions
+        .iter()
+        .map(Restriction::encode)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse the format written by [`encode_restrictions`]. Returns `None` if any rule is
+/// malformed or if there are more than [`MAX_RESTRICTIONS`].
+pub fn parse_restrictions(value: &str) -> Option<Vec<Restriction>> {
+    if value.trim().is_empty() {
+        return Some(Vec::new());
+    }
+
+    let restrictions = value
+        .split(',')
+        .map(Restriction::parse)
+        .collect::<Option<Vec<_>>>()?;
+    (restrictions.len() <= MAX_RESTRICTIONS).then_some(restrictions)
+}
+
+/// Where the controller is, in degrees (north and east positive), for sunrise and sunset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    /// Buenos Aires, used until a location is configured
+    pub const DEFAULT: Location = Location {
+        latitude: -34.6037,
+        longitude: -58.3816,
+    };
+
+    pub fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.latitude) && (-180.0..=180.0).contains(&self.longitude)
+    }
+
+    /// Sunrise and sunset on `day` in local time, `None` during polar day or night
+    pub fn sun_times(&self, day: i64) -> Option<SunTimes> {
+        solar::sun_times(day, self.latitude, self.longitude, LOCAL_UTC_OFFSET)
+    }
+}
+
+/// How many days ahead [`ControllerSchedule::upcoming`] looks for runs
+pub const FORECAST_DAYS: i64 = 31;
+
+/// Why a run is planned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// One of the zone's own slots
+    Slot,
+    /// The sequential program
+    Program,
+    /// Making up a run missed while the controller was off
+    CatchUp,
+}
+
+impl Reason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reason::Slot => "slot",
+            Reason::Program => "program",
+            Reason::CatchUp => "catch_up",
+        }
+    }
+}
+
+/// How far the controller can trust its clock. There is no RTC battery, so after a restart the
+/// time saved in NVS is only where the clock was when it went off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockTrust {
+    /// No time was ever set
+    NeverSynced,
+    /// Restored from the time saved before the restart, behind by however long it was off
+    Restored,
+    /// Set from `/set_time` since the restart
+    Synced,
+}
+
+/// 2020-01-01 in Unix seconds. The main loop saves the time even if it was never set, and
+/// then it is just the uptime, so anything earlier can't come from a synced clock.
+pub const MIN_SYNCED_TIME: i64 = 1_577_836_800;
+
+impl ClockTrust {
+    /// Trust at boot, given the absolute time saved in NVS (missing if never saved)
+    pub fn at_boot(saved_time: Option<i64>) -> Self {
+        match saved_time {
+            Some(time) if time >= MIN_SYNCED_TIME => ClockTrust::Restored,
+            _ => ClockTrust::NeverSynced,
+        }
+    }
+
+    /// Only a synced clock may start automatic runs, catch up or time out manual mode
+    pub fn is_trusted(self) -> bool {
+        self == ClockTrust::Synced
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ClockTrust::NeverSynced => "never_synced",
+            ClockTrust::Restored => "restored",
+            ClockTrust::Synced => "synced",
+        }
+    }
+}
+
+/// Controller-wide settings applied on top of the zones' own schedules
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerSchedule {
+    /// Replaces the zones' own slots while enabled
+    pub program: Program,
+    /// Maximum number of valves open at once, 0 means no limit
+    pub max_concurrent: u8,
+    /// Seconds between one zone closing and another opening, 0 means none
+    pub zone_gap: u32,
+    /// Seasonal adjustment, every duration is scaled by this percentage (100 = as stored)
+    pub budget_percent: u16,
+    /// Rain delay: no automatic run may start before this time (local seconds)
+    pub rain_delay_until: Option<i64>,
+    /// Resolves sunrise and sunset relative slots
+    pub location: Location,
+    /// Municipal rules, no valve may open while one of them forbids watering
+    pub restrictions: Vec<Restriction>,
+    /// Schedule sets for parts of the year, the first one covering a day is used
+    pub seasons: Vec<Season>,
+    /// Runs missed while the controller was off are made up if they were due at most this
+    /// many seconds ago, 0 turns catch-up off
+    pub catch_up_grace: u32,
+    /// Make-up runs found by [`catch_up_runs`], waiting to run or running
+    pub catch_up: Vec<Run>,
+}
+
+impl ControllerSchedule {
+    /// Copy of `zones` with every slot duration and volume scaled by the water budget,
+    /// durations capped at 24h. The stored schedules are left untouched.
+    pub fn budgeted(&self, zones: &[ZoneSchedule]) -> Vec<ZoneSchedule> {
+        zones
+            .iter()
+            .map(|zone| {
+                let mut zone = zone.clone();
+                for slot in &mut zone.slots {
+                    let scaled = u64::from(slot.duration) * u64::from(self.budget_percent) / 100;
+                    slot.duration = scaled.min(u64::from(SECONDS_PER_DAY)) as u32;
+                }
+                zone.volume = zone.volume.map(|liters| {
+                    (u64::from(liters) * u64::from(self.budget_percent) / 100) as u32
+                });
+                zone
+            })
+            .collect()
+    }
+
+    /// Season in effect on `day`, `None` means the zones' own slots
+    pub fn season_at(&self, day: i64) -> Option<&Season> {
+        self.seasons.iter().find(|season| season.covers(day))
+    }
+
+    /// Schedules of `zones` as they run on `day`: the season's slots, then the water budget
+    pub fn zones_on(&self, day: i64, zones: &[ZoneSchedule]) -> Vec<ZoneSchedule> {
+        match self.season_at(day) {
+            Some(season) => self.budgeted(&season.apply(zones)),
+            None => self.budgeted(zones),
+        }
+    }
+
+    /// The sequential program with the start time of the season in effect on `day`
+    pub fn program_on(&self, day: i64) -> Program {
+        let mut program = self.program.clone();
+        if let Some(start) = self.season_at(day).and_then(|season| season.program_start) {
+            program.start_time = start;
+        }
+        program
+    }
+
+    /// First restriction that forbids watering at `now`, if any
+    pub fn restriction_at(&self, now: i64) -> Option<&Restriction> {
+        self.restrictions
+            .iter()
+            .find(|restriction| restriction.forbids(now))
+    }
+
+    /// Rain delay still in effect at `now`. Once it passes it is simply ignored.
+    pub fn rain_delay(&self, now: i64) -> Option<i64> {
+        self.rain_delay_until.filter(|&until| now < until)
+    }
+
+    /// Every run starting on `day - 1` or `day`, which covers anything still going on `day`,
+    /// plus pending catch-up runs. Runs meant to start during a rain delay are dropped, not
+    /// postponed.
+    pub fn runs(&self, day: i64, zones: &[ZoneSchedule]) -> Vec<Run> {
+        let mut runs = self.scheduled_runs(day, zones);
+        runs.extend(
+            self.catch_up
+                .iter()
+                .filter(|run| !self.is_rain_delayed(run)),
+        );
+
+        limit_concurrency(runs, self.max_concurrent, self.zone_gap)
+    }
+
+    /// The next `count` runs still going or starting after `now`, in start order, looking at
+    /// most [`FORECAST_DAYS`] ahead. Runs are planned as in [`Self::runs`].
+    pub fn upcoming(&self, now: i64, zones: &[ZoneSchedule], count: usize) -> Vec<(Run, Reason)> {
+        let (today, _) = split_local_timestamp(now);
+        let mut upcoming = Vec::new();
+
+        for day in today..today + FORECAST_DAYS {
+            if upcoming.len() >= count {
+                break;
+            }
+            // `runs(day)` also holds the previous day's runs, take each run from its own day
+            // since that is where everything queued ahead of it is planned too
+            let first_day = if day == today { day - 1 } else { day };
+            upcoming.extend(self.runs(day, zones).into_iter().filter(|run| {
+                let (start_day, _) = split_local_timestamp(run.requested_start);
+                (first_day..=day).contains(&start_day) && run.end() > now
+            }));
+        }
+
+        upcoming.sort_by_key(|run| (run.start, run.zone));
+        upcoming.truncate(count);
+        upcoming
+            .into_iter()
+            .map(|run| (run, self.reason(&run)))
+            .collect()
+    }
+
+    fn reason(&self, run: &Run) -> Reason {
+        if self.catch_up.iter().any(|pending| {
+            pending.zone == run.zone && pending.requested_start == run.requested_start
+        }) {
+            Reason::CatchUp
+        } else if self.program.enabled {
+            Reason::Program
+        } else {
+            Reason::Slot
+        }
+    }
+
+    fn is_rain_delayed(&self, run: &Run) -> bool {
+        self.rain_delay_until
+            .is_some_and(|until| run.requested_start < until)
+    }
+
+    /// Runs as the schedules ask for them, before catch-up and the concurrency limit
+    pub fn scheduled_runs(&self, day: i64, zones: &[ZoneSchedule]) -> Vec<Run> {
+        (day - 1..=day)
+            .flat_map(|start_day| {
+                // Seasons and solar slots change with the date, so use the day runs start on
+                let sun = self.location.sun_times(start_day);
+                let zones: &Vec<ZoneSchedule> = &self
+                    .zones_on(start_day, zones)
+                    .iter()
+                    .map(|zone| zone.resolved(sun))
+                    .collect();
+
+                if self.program.enabled {
+                    self.program_on(start_day)
+                        .runs(start_day, zones, self.zone_gap)
+                } else {
+                    zones
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(zone, schedule)| schedule.runs(zone, start_day))
+                        .collect()
+                }
+            })
+            .filter(|run: &Run| !self.is_rain_delayed(run))
+            .collect()
+    }
+}
+
+/// Runs from `planned` missed in a gap where the controller wasn't running, e.g. a power cut:
+/// due after `last_seen` and already over by `now`, and due no more than `grace` seconds ago.
+/// Runs still going at `now` are left out since they simply resume.
+///
+/// The missed runs are lined up one after the other from `now`, keeping the soak time
+/// between cycles of the same zone.
+pub fn catch_up_runs(planned: &[Run], last_seen: i64, now: i64, grace: u32) -> Vec<Run> {
+    let oldest_due = now - i64::from(grace);
+    let mut missed: Vec<Run> = planned
+        .iter()
+        .filter(|run| run.requested_start > last_seen && run.requested_start >= oldest_due)
+        .filter(|run| run.requested_start + i64::from(run.duration) <= now)
+        .copied()
+        .collect();
+    missed.sort_by_key(|run| (run.requested_start, run.zone));
+
+    let mut next_start = now;
+    let mut lined_up: Vec<Run> = Vec::with_capacity(missed.len());
+    for run in missed {
+        let after_soak = lined_up
+            .iter()
+            .rev()
+            .find(|other| other.zone == run.zone)
+            .map_or(next_start, |other| other.end() + i64::from(run.soak));
+
+        let mut catch_up = run;
+        catch_up.start = next_start.max(after_soak);
+        catch_up.requested_start = catch_up.start;
+        next_start = catch_up.end();
+        lined_up.push(catch_up);
+    }
+
+    lined_up
+}
+
+/// Encode slots as `init:duration` pairs separated by commas, e.g. `"21600:2700,64800:1200"`.
+/// Solar slots use their anchor instead of the start time, e.g. `"sr-1800:2700"`.
+pub fn encode_slots(slots: &[Slot]) -> String {
+    slots
+        .iter()
+        .map(|slot| {
+            let start = slot
+                .anchor
+                .encode()
+                .unwrap_or_else(|| slot.init_time.to_string());
+            format!("{}:{}", start, slot.duration)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse the format written by [`encode_slots`]. Returns `None` if any pair is malformed,
+/// out of range or if there are more than [`MAX_SLOTS`] pairs.
+pub fn parse_slots(value: &str) -> Option<Vec<Slot>> {
+    if value.trim().is_empty() {
+        return Some(Vec::new());
+    }
+
+    let slots = value
+        .split(',')
+        .map(|pair| {
+            let (start, duration) = pair.trim().split_once(':')?;
+            let duration = duration.parse().ok()?;
+            let slot = match Anchor::parse(start) {
+                Some(anchor) => Slot::solar(anchor, duration),
+                None => Slot::new(start.parse().ok()?, duration),
+            };
+            (slot.init_time < SECONDS_PER_DAY && slot.duration <= SECONDS_PER_DAY).then_some(slot)
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    (slots.len() <= MAX_SLOTS).then_some(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn hm(hours: u32, minutes: u32) -> u32 {
+        hours * 3600 + minutes * 60
+    }
+
+    fn is_within_window(current_time: u32, init_time: u32, duration: u32) -> bool {
+        let schedule = ZoneSchedule::new(vec![Slot::new(init_time, duration)]);
+        is_on(&schedule, MONDAY, current_time)
+    }
+
+    /// Zones running at `time` on `day`
+    fn active(
+        controller: &ControllerSchedule,
+        zones: &[ZoneSchedule],
+        day: i64,
+        time: u32,
+    ) -> Vec<usize> {
+        let now = local_seconds(day, time);
+        let mut active: Vec<usize> = controller
+            .runs(day, zones)
+            .iter()
+            .filter(|run| run.is_active(now))
+            .map(|run| run.zone)
+            .collect();
+        active.sort_unstable();
+        active
+    }
+
+    fn independent() -> ControllerSchedule {
+        ControllerSchedule {
+            program: Program {
+                enabled: false,
+                start_time: 0,
+                order: Vec::new(),
+            },
+            max_concurrent: 0,
+            zone_gap: 0,
+            budget_percent: 100,
+            rain_delay_until: None,
+            location: Location::DEFAULT,
+            restrictions: Vec::new(),
+            seasons: Vec::new(),
+            catch_up_grace: 0,
+            catch_up: Vec::new(),
+        }
+    }
+
+    fn is_on(schedule: &ZoneSchedule, day: i64, time: u32) -> bool {
+        !active(&independent(), std::slice::from_ref(schedule), day, time).is_empty()
+    }
+
+    #[test]
+    fn same_day_window() {
+        let (init, dur) = (hm(6, 15), 45 * 60);
+        assert!(!is_within_window(hm(6, 14), init, dur));
+        assert!(is_within_window(hm(6, 15), init, dur));
+        assert!(is_within_window(hm(6, 59), init, dur));
+        assert!(!is_within_window(hm(7, 0), init, dur));
+    }
+
+    #[test]
+    fn window_crossing_midnight() {
+        let (init, dur) = (hm(23, 0), 2 * 3600);
+        assert!(!is_within_window(hm(22, 59), init, dur));
+        assert!(is_within_window(hm(23, 0), init, dur));
+        assert!(is_within_window(hm(23, 59) + 59, init, dur));
+        assert!(is_within_window(0, init, dur));
+        assert!(is_within_window(hm(0, 59), init, dur));
+        assert!(!is_within_window(hm(1, 0), init, dur));
+        assert!(!is_within_window(hm(12, 0), init, dur));
+    }
+
+    #[test]
+    fn window_ending_exactly_at_midnight() {
+        let (init, dur) = (hm(22, 0), 2 * 3600);
+        assert!(is_within_window(hm(23, 59) + 59, init, dur));
+        assert!(!is_within_window(0, init, dur));
+    }
+
+    #[test]
+    fn window_starting_at_midnight() {
+        assert!(is_within_window(0, 0, 60));
+        assert!(!is_within_window(hm(23, 59), 0, 60));
+    }
+
+    #[test]
+    fn run_crossing_midnight_keeps_its_start() {
+        let schedule = ZoneSchedule::new(vec![Slot::new(hm(23, 0), 2 * 3600)]);
+        let run = schedule.runs(0, MONDAY)[0];
+
+        assert_eq!(run.start, local_seconds(MONDAY, hm(23, 0)));
+        assert_eq!(run.end(), local_seconds(MONDAY + 1, hm(1, 0)));
+
+        // Time into the run counts across midnight
+        let after_midnight = local_seconds(MONDAY + 1, hm(0, 30));
+        assert!(run.is_active(after_midnight));
+        assert_eq!(after_midnight - run.start, 90 * 60);
+        let before_midnight = local_seconds(MONDAY, hm(23, 30));
+        assert!(run.is_active(before_midnight));
+        assert_eq!(before_midnight - run.start, 30 * 60);
+
+        assert!(!run.is_active(local_seconds(MONDAY + 1, hm(1, 30))));
+    }
+
+    #[test]
+    fn zero_duration_never_runs() {
+        assert!(!is_within_window(hm(5, 0), hm(5, 0), 0));
+    }
+
+    #[test]
+    fn full_day_window_always_runs() {
+        for init in [0, hm(5, 0), hm(23, 59)] {
+            for now in [0, init, hm(12, 0), SECONDS_PER_DAY - 1] {
+                assert!(is_within_window(now, init, SECONDS_PER_DAY));
+            }
+        }
+    }
+
+    #[test]
+    fn durations_longer_than_a_day_are_capped() {
+        assert!(is_within_window(hm(4, 59), hm(5, 0), 3 * SECONDS_PER_DAY));
+        let schedule = ZoneSchedule::new(vec![Slot::new(hm(5, 0), u32::MAX)]);
+        let run = schedule.runs(0, MONDAY)[0];
+        assert_eq!(run.duration, SECONDS_PER_DAY);
+
+        // A minute before the next day's start, a full day less a minute into the run
+        let now = local_seconds(MONDAY + 1, hm(4, 59));
+        assert!(run.is_active(now));
+        assert_eq!(now - run.start, i64::from(SECONDS_PER_DAY - 60));
+        assert!(!run.is_active(local_seconds(MONDAY + 1, hm(5, 0))));
+    }
+
+    #[test]
+    fn out_of_range_init_time_wraps() {
+        assert!(is_within_window(hm(0, 30), SECONDS_PER_DAY, 3600));
+    }
+
+    // 2024-01-01 was a Monday
+    const MONDAY: i64 = 19723;
+
+    #[test]
+    fn every_slot_runs() {
+        let schedule = ZoneSchedule::new(vec![
+            Slot::new(hm(6, 0), 30 * 60),
+            Slot::new(hm(20, 0), 15 * 60),
+        ]);
+        assert!(is_on(&schedule, MONDAY, hm(6, 10)));
+        assert!(is_on(&schedule, MONDAY, hm(20, 14)));
+        assert!(!is_on(&schedule, MONDAY, hm(12, 0)));
+        assert!(!is_on(&ZoneSchedule::new(Vec::new()), MONDAY, hm(12, 0)));
+    }
+
+    #[test]
+    fn local_timestamp_split() {
+        assert_eq!(split_local_timestamp(0), (0, 0));
+        assert_eq!(split_local_timestamp(90_000), (1, 3600));
+        assert_eq!(split_local_timestamp(-1), (-1, SECONDS_PER_DAY - 1));
+        assert_eq!(weekday(0), 3);
+        assert_eq!(weekday(MONDAY), 0);
+        assert_eq!(weekday(MONDAY + 6), 6);
+        assert_eq!(weekday(-1), 2);
+    }
+
+    #[test]
+    fn weekday_mask_limits_days() {
+        let mut schedule = ZoneSchedule::new(vec![Slot::new(hm(6, 0), 3600)]);
+        schedule.weekdays = 0b0010101; // Mon, Wed, Fri
+
+        assert!(is_on(&schedule, MONDAY, hm(6, 30)));
+        assert!(!is_on(&schedule, MONDAY + 1, hm(6, 30)));
+        assert!(is_on(&schedule, MONDAY + 2, hm(6, 30)));
+        assert!(!is_on(&schedule, MONDAY + 6, hm(6, 30)));
+    }
+
+    #[test]
+    fn midnight_window_uses_start_day() {
+        let mut schedule = ZoneSchedule::new(vec![Slot::new(hm(23, 0), 2 * 3600)]);
+        schedule.weekdays = 0b0000001; // Mon only
+
+        assert!(is_on(&schedule, MONDAY, hm(23, 30)));
+        // Tuesday early morning is still Monday's run
+        assert!(is_on(&schedule, MONDAY + 1, hm(0, 30)));
+        // Monday early morning would be Sunday's run
+        assert!(!is_on(&schedule, MONDAY, hm(0, 30)));
+        assert!(!is_on(&schedule, MONDAY + 1, hm(23, 30)));
+    }
+
+    #[test]
+    fn interval_counts_from_anchor() {
+        let mut schedule = ZoneSchedule::new(vec![Slot::new(hm(16, 0), 5 * 3600)]);
+        schedule.weekdays = 0; // ignored while an interval is set
+        schedule.interval = Some(Interval {
+            anchor_day: MONDAY,
+            every_days: 3,
+        });
+
+        assert!(!schedule.runs_on(MONDAY - 3));
+        assert!(schedule.runs_on(MONDAY));
+        assert!(!schedule.runs_on(MONDAY + 1));
+        assert!(!schedule.runs_on(MONDAY + 2));
+        assert!(schedule.runs_on(MONDAY + 3));
+        assert!(schedule.runs_on(MONDAY + 300));
+
+        assert!(is_on(&schedule, MONDAY + 3, hm(17, 0)));
+        assert!(!is_on(&schedule, MONDAY + 4, hm(17, 0)));
+    }
+
+    #[test]
+    fn interval_window_crossing_midnight() {
+        let mut schedule = ZoneSchedule::new(vec![Slot::new(hm(22, 0), 4 * 3600)]);
+        schedule.interval = Some(Interval {
+            anchor_day: MONDAY,
+            every_days: 2,
+        });
+
+        assert!(is_on(&schedule, MONDAY + 1, hm(1, 0)));
+        assert!(!is_on(&schedule, MONDAY + 2, hm(1, 0)));
+    }
+
+    #[test]
+    fn next_run_day() {
+        let mut schedule = ZoneSchedule::new(vec![Slot::new(hm(6, 0), 3600)]);
+        assert_eq!(schedule.next_run_day(MONDAY), Some(MONDAY));
+
+        schedule.weekdays = 0b0010000; // Fri
+        assert_eq!(schedule.next_run_day(MONDAY), Some(MONDAY + 4));
+        assert_eq!(schedule.next_run_day(MONDAY + 5), Some(MONDAY + 11));
+
+        schedule.weekdays = 0;
+        assert_eq!(schedule.next_run_day(MONDAY), None);
+
+        schedule.interval = Some(Interval {
+            anchor_day: MONDAY + 10,
+            every_days: 3,
+        });
+        assert_eq!(schedule.next_run_day(MONDAY), Some(MONDAY + 10));
+        assert_eq!(schedule.next_run_day(MONDAY + 10), Some(MONDAY + 10));
+        assert_eq!(schedule.next_run_day(MONDAY + 11), Some(MONDAY + 13));
+        assert_eq!(schedule.next_run_day(MONDAY + 13), Some(MONDAY + 13));
+    }
+
+    #[test]
+    fn weekdays_parse() {
+        assert_eq!(parse_weekdays("21"), Some(0b0010101));
+        assert_eq!(parse_weekdays("mon,wed,fri"), Some(0b0010101));
+        assert_eq!(parse_weekdays("Sat,Sunday"), Some(0b1100000));
+        assert_eq!(parse_weekdays("0"), Some(0));
+        assert_eq!(parse_weekdays("128"), None);
+        assert_eq!(parse_weekdays("mon,xyz"), None);
+    }
+
+    #[test]
+    fn slots_round_trip() {
+        let slots = vec![Slot::new(hm(6, 0), 30 * 60), Slot::new(hm(23, 0), 2 * 3600)];
+        let encoded = encode_slots(&slots);
+        assert_eq!(encoded, "21600:1800,82800:7200");
+        assert_eq!(parse_slots(&encoded), Some(slots));
+        assert_eq!(parse_slots(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_slots_are_rejected() {
+        assert_eq!(parse_slots("21600"), None);
+        assert_eq!(parse_slots("21600:abc"), None);
+        assert_eq!(parse_slots("86400:60"), None);
+        assert_eq!(parse_slots("0:86401"), None);
+        assert_eq!(parse_slots("0:1,0:1,0:1,0:1,0:1"), None);
+        assert_eq!(parse_slots("sr-43201:60"), None);
+        assert_eq!(parse_slots("sx:60"), None);
+    }
+
+    #[test]
+    fn solar_slots_round_trip() {
+        let slots = vec![
+            Slot::solar(Anchor::Sunrise(-1800), 2700),
+            Slot::solar(Anchor::Sunset(600), 1200),
+            Slot::new(hm(6, 0), 60),
+        ];
+        let encoded = encode_slots(&slots);
+        assert_eq!(encoded, "sr-1800:2700,ss+600:1200,21600:60");
+        assert_eq!(parse_slots(&encoded), Some(slots));
+        assert_eq!(
+            parse_slots("sr:60"),
+            Some(vec![Slot::solar(Anchor::Sunrise(0), 60)])
+        );
+    }
+
+    #[test]
+    fn solar_slots_resolve_around_the_sun() {
+        let sun = Some(SunTimes {
+            sunrise: hm(6, 0),
+            sunset: hm(19, 0),
+        });
+        let before_sunrise = Slot::solar(Anchor::Sunrise(-1800), 600);
+        let after_sunset = Slot::solar(Anchor::Sunset(5 * 3600 + 30 * 60), 600);
+
+        assert_eq!(before_sunrise.resolve(sun), Some(Slot::new(hm(5, 30), 600)));
+        assert_eq!(after_sunset.resolve(sun), Some(Slot::new(hm(0, 30), 600)));
+        assert_eq!(before_sunrise.resolve(None), None);
+        assert_eq!(Slot::new(1, 2).resolve(None), Some(Slot::new(1, 2)));
+    }
+
+    #[test]
+    fn sunrise_slot_follows_the_seasons() {
+        let zones = vec![ZoneSchedule::new(vec![Slot::solar(
+            Anchor::Sunrise(-1800),
+            20 * 60,
+        )])];
+        let controller = independent();
+        // 2024-06-21 and 2024-12-21, sunrise around 8:00 and 5:37 in Buenos Aires
+        let (winter, summer) = (19895, 20078);
+
+        assert_eq!(active(&controller, &zones, winter, hm(7, 40)), vec![0]);
+        assert!(active(&controller, &zones, summer, hm(7, 40)).is_empty());
+        assert_eq!(active(&controller, &zones, summer, hm(5, 15)), vec![0]);
+    }
+
+    fn program_zones() -> Vec<ZoneSchedule> {
+        vec![
+            ZoneSchedule::new(vec![Slot::new(hm(5, 0), 75 * 60)]),
+            ZoneSchedule::new(vec![Slot::new(hm(6, 15), 45 * 60)]),
+            ZoneSchedule::new(vec![Slot::new(hm(7, 0), 40 * 60)]),
+        ]
+    }
+
+    fn program(start_time: u32, order: Vec<usize>) -> ControllerSchedule {
+        ControllerSchedule {
+            program: Program {
+                enabled: true,
+                start_time,
+                order,
+            },
+            ..independent()
+        }
+    }
+
+    #[test]
+    fn program_runs_zones_in_order() {
+        let zones = program_zones();
+        let controller = program(hm(5, 0), vec![2, 0, 1]);
+
+        assert_eq!(
+            controller.program.runs(MONDAY, &zones, 0),
+            vec![
+                Run::new(2, local_seconds(MONDAY, hm(5, 0)), 40 * 60),
+                Run::new(0, local_seconds(MONDAY, hm(5, 40)), 75 * 60),
+                Run::new(1, local_seconds(MONDAY, hm(6, 55)), 45 * 60),
+            ]
+        );
+        assert!(active(&controller, &zones, MONDAY, hm(4, 59)).is_empty());
+        assert_eq!(active(&controller, &zones, MONDAY, hm(5, 0)), vec![2]);
+        assert_eq!(active(&controller, &zones, MONDAY, hm(5, 39)), vec![2]);
+        assert_eq!(active(&controller, &zones, MONDAY, hm(5, 40)), vec![0]);
+        assert_eq!(active(&controller, &zones, MONDAY, hm(6, 55)), vec![1]);
+        assert!(active(&controller, &zones, MONDAY, hm(7, 40)).is_empty());
+    }
+
+    #[test]
+    fn program_skips_zones_not_running_today() {
+        let mut zones = program_zones();
+        zones[2].weekdays = 0b0000010; // Tue only
+        let controller = program(hm(5, 0), vec![2, 0, 1]);
+
+        assert_eq!(active(&controller, &zones, MONDAY, hm(5, 0)), vec![0]);
+        assert_eq!(active(&controller, &zones, MONDAY, hm(6, 15)), vec![1]);
+        assert_eq!(active(&controller, &zones, MONDAY + 1, hm(5, 0)), vec![2]);
+    }
+
+    #[test]
+    fn program_leaves_out_unlisted_zones() {
+        let zones = program_zones();
+        let controller = program(hm(5, 0), vec![1, 7]);
+
+        assert_eq!(
+            controller.program.runs(MONDAY, &zones, 0),
+            vec![Run::new(1, local_seconds(MONDAY, hm(5, 0)), 45 * 60)]
+        );
+        assert!(active(&controller, &zones, MONDAY, hm(6, 0)).is_empty());
+    }
+
+    #[test]
+    fn program_crossing_midnight() {
+        let zones = program_zones();
+        let controller = program(hm(23, 0), vec![0, 1]);
+
+        assert_eq!(active(&controller, &zones, MONDAY, hm(23, 30)), vec![0]);
+        assert_eq!(active(&controller, &zones, MONDAY + 1, hm(0, 30)), vec![1]);
+        assert!(active(&controller, &zones, MONDAY + 1, hm(1, 0)).is_empty());
+    }
+
+    fn overlapping_zones() -> Vec<ZoneSchedule> {
+        vec![
+            ZoneSchedule::new(vec![Slot::new(hm(6, 0), 3600)]),
+            ZoneSchedule::new(vec![Slot::new(hm(6, 30), 1800)]),
+            ZoneSchedule::new(vec![Slot::new(hm(6, 45), 600)]),
+        ]
+    }
+
+    /// Run of `zone` requested on `day`
+    fn run_of(runs: &[Run], zone: usize, day: i64) -> Run {
+        *runs
+            .iter()
+            .find(|run| run.zone == zone && split_local_timestamp(run.requested_start).0 == day)
+            .unwrap()
+    }
+
+    #[test]
+    fn overlapping_zones_run_together_without_limit() {
+        let zones = overlapping_zones();
+
+        assert_eq!(
+            active(&independent(), &zones, MONDAY, hm(6, 50)),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn queued_run_keeps_full_duration() {
+        let zones = overlapping_zones();
+        let controller = ControllerSchedule {
+            max_concurrent: 1,
+            ..independent()
+        };
+        let runs = controller.runs(MONDAY, &zones);
+        let now = local_seconds(MONDAY, hm(6, 50));
+
+        let second = run_of(&runs, 1, MONDAY);
+        let third = run_of(&runs, 2, MONDAY);
+        assert_eq!(second.requested_start, local_seconds(MONDAY, hm(6, 30)));
+        assert_eq!(second.start, local_seconds(MONDAY, hm(7, 0)));
+        assert_eq!(second.end(), local_seconds(MONDAY, hm(7, 30)));
+        assert_eq!(third.start, local_seconds(MONDAY, hm(7, 30)));
+        assert_eq!(third.end(), local_seconds(MONDAY, hm(7, 40)));
+        assert!(second.is_queued(now) && third.is_queued(now));
+        assert!(!run_of(&runs, 0, MONDAY).is_queued(now));
+        assert_eq!(active(&controller, &zones, MONDAY, hm(6, 50)), vec![0]);
+        assert_eq!(active(&controller, &zones, MONDAY, hm(7, 35)), vec![2]);
+        assert!(active(&controller, &zones, MONDAY, hm(7, 40)).is_empty());
+    }
+
+    #[test]
+    fn max_concurrent_two() {
+        let zones = vec![
+            ZoneSchedule::new(vec![Slot::new(hm(6, 0), 3600)]),
+            ZoneSchedule::new(vec![Slot::new(hm(6, 10), 3600)]),
+            ZoneSchedule::new(vec![Slot::new(hm(6, 20), 600)]),
+        ];
+        let controller = ControllerSchedule {
+            max_concurrent: 2,
+            ..independent()
+        };
+
+        assert_eq!(active(&controller, &zones, MONDAY, hm(6, 25)), vec![0, 1]);
+        assert_eq!(active(&controller, &zones, MONDAY, hm(7, 5)), vec![1, 2]);
+        assert!(active(&controller, &zones, MONDAY, hm(7, 10)).is_empty());
+    }
+
+    #[test]
+    fn non_overlapping_zones_are_not_delayed() {
+        let zones = program_zones();
+        let controller = ControllerSchedule {
+            max_concurrent: 1,
+            ..independent()
+        };
+
+        for run in controller.runs(MONDAY, &zones) {
+            assert_eq!(run.start, run.requested_start);
+        }
+    }
+
+    #[test]
+    fn cycles_split_evenly() {
+        let cycle_soak = CycleSoak {
+            max_cycle: 20 * 60,
+            soak: 30 * 60,
+        };
+        assert_eq!(cycle_soak.cycles(75 * 60), vec![1125; 4]);
+        assert_eq!(cycle_soak.cycles(20 * 60 + 1), vec![601, 600]);
+        assert_eq!(cycle_soak.cycles(40 * 60), vec![1200, 1200]);
+        assert_eq!(cycle_soak.cycles(10 * 60), vec![600]);
+        assert_eq!(cycle_soak.cycles(75 * 60).iter().sum::<u32>(), 75 * 60);
+    }
+
+    #[test]
+    fn volume_is_split_between_cycles() {
+        let mut zone = soaked(hm(5, 0), 40 * 60);
+        zone.volume = Some(300);
+        let runs = zone.cycles(0, local_seconds(MONDAY, hm(5, 0)), 45 * 60);
+
+        assert_eq!(
+            runs.iter().map(|run| run.volume_ml).collect::<Vec<_>>(),
+            vec![Some(100_000); 3]
+        );
+
+        zone.cycle_soak = None;
+        let runs = zone.cycles(0, local_seconds(MONDAY, hm(5, 0)), 45 * 60);
+        assert_eq!(runs[0].volume_ml, Some(300_000));
+
+        let controller = ControllerSchedule {
+            budget_percent: 50,
+            ..independent()
+        };
+        assert_eq!(controller.budgeted(&[zone])[0].volume, Some(150));
+    }
+
+    fn soaked(init_time: u32, duration: u32) -> ZoneSchedule {
+        ZoneSchedule {
+            cycle_soak: Some(CycleSoak {
+                max_cycle: 20 * 60,
+                soak: 30 * 60,
+            }),
+            ..ZoneSchedule::new(vec![Slot::new(init_time, duration)])
+        }
+    }
+
+    #[test]
+    fn cycle_and_soak_runs() {
+        let schedule = soaked(hm(5, 0), 40 * 60);
+
+        assert!(is_on(&schedule, MONDAY, hm(5, 10)));
+        assert!(!is_on(&schedule, MONDAY, hm(5, 30))); // soaking
+        assert!(is_on(&schedule, MONDAY, hm(5, 50)));
+        assert!(!is_on(&schedule, MONDAY, hm(6, 10)));
+        assert_eq!(schedule.runs(0, MONDAY)[1].soak, 30 * 60);
+    }
+
+    #[test]
+    fn program_waits_for_last_cycle() {
+        let zones = vec![
+            soaked(hm(5, 0), 40 * 60),
+            ZoneSchedule::new(vec![Slot::new(hm(7, 0), 600)]),
+        ];
+        let controller = program(hm(5, 0), vec![0, 1]);
+
+        assert!(active(&controller, &zones, MONDAY, hm(5, 30)).is_empty());
+        assert_eq!(active(&controller, &zones, MONDAY, hm(6, 15)), vec![1]);
+    }
+
+    #[test]
+    fn queued_cycle_keeps_its_soak() {
+        let zones = vec![
+            soaked(hm(5, 0), 40 * 60),
+            ZoneSchedule::new(vec![Slot::new(hm(5, 10), 3600)]),
+        ];
+        let controller = ControllerSchedule {
+            max_concurrent: 1,
+            ..independent()
+        };
+        let runs = controller.runs(MONDAY, &zones);
+        let second_cycle = runs
+            .iter()
+            .find(|run| {
+                run.zone == 0 && run.soak > 0 && run.requested_start > local_seconds(MONDAY, 0)
+            })
+            .unwrap();
+
+        // First cycle 5:00-5:20, the other zone 5:20-6:20, second cycle right after
+        assert_eq!(second_cycle.start, local_seconds(MONDAY, hm(6, 20)));
+        assert_eq!(active(&controller, &zones, MONDAY, hm(6, 30)), vec![0]);
+    }
+
+    #[test]
+    fn budget_scales_durations() {
+        let zones = program_zones();
+        let controller = ControllerSchedule {
+            budget_percent: 50,
+            ..independent()
+        };
+        let runs = controller.runs(MONDAY, &zones);
+
+        assert_eq!(run_of(&runs, 0, MONDAY).duration, 75 * 30);
+        assert_eq!(run_of(&runs, 1, MONDAY).duration, 45 * 30);
+        assert!(active(&controller, &zones, MONDAY, hm(5, 40)).is_empty());
+        // Stored schedules are untouched
+        assert_eq!(zones[0].slots[0].duration, 75 * 60);
+    }
+
+    #[test]
+    fn budget_moves_program_zones_up_and_caps_at_a_day() {
+        let zones = program_zones();
+        let controller = ControllerSchedule {
+            budget_percent: 200,
+            ..program(hm(5, 0), vec![0, 1])
+        };
+        let runs = controller
+            .program
+            .runs(MONDAY, &controller.budgeted(&zones), 0);
+        assert_eq!(runs[1].start, local_seconds(MONDAY, hm(7, 30)));
+
+        let long = vec![ZoneSchedule::new(vec![Slot::new(0, 20 * 3600)])];
+        assert_eq!(
+            controller.budgeted(&long)[0].slots[0].duration,
+            SECONDS_PER_DAY
+        );
+    }
+
+    #[test]
+    fn rain_delay_skips_runs_until_it_expires() {
+        let zones = program_zones();
+        let controller = ControllerSchedule {
+            rain_delay_until: Some(local_seconds(MONDAY, hm(6, 0))),
+            ..independent()
+        };
+
+        // costado (5:00) is skipped entirely, toberas (6:15) runs as usual
+        assert!(active(&controller, &zones, MONDAY, hm(6, 5)).is_empty());
+        assert_eq!(active(&controller, &zones, MONDAY, hm(6, 20)), vec![1]);
+        assert_eq!(active(&controller, &zones, MONDAY + 1, hm(5, 10)), vec![0]);
+
+        assert!(controller
+            .rain_delay(local_seconds(MONDAY, hm(5, 0)))
+            .is_some());
+        assert!(controller
+            .rain_delay(local_seconds(MONDAY, hm(6, 0)))
+            .is_none());
+    }
+
+    #[test]
+    fn month_and_day_of_day_numbers() {
+        assert_eq!(month_and_day(0), (1, 1));
+        assert_eq!(month_and_day(MONDAY), (1, 1)); // 2024-01-01
+        assert_eq!(month_and_day(MONDAY + 59), (2, 29)); // leap day
+        assert_eq!(month_and_day(MONDAY + 365), (12, 31));
+        assert_eq!(month_and_day(-1), (12, 31));
+    }
+
+    #[test]
+    fn odd_and_even_days() {
+        let odd = Restriction::parse("odd").unwrap();
+        let even = Restriction::parse("even").unwrap();
+        // 2024-01-01 is odd
+        let first = local_seconds(MONDAY, hm(6, 0));
+        let second = local_seconds(MONDAY + 1, hm(6, 0));
+
+        assert!(!odd.forbids(first) && odd.forbids(second));
+        assert!(even.forbids(first) && !even.forbids(second));
+    }
+
+    #[test]
+    fn forbidden_hours_and_validity() {
+        // No daytime watering from December to February
+        let summer = Restriction::parse("no:36000-68400@1201-0229").unwrap();
+        assert_eq!(
+            summer.rule,
+            Rule::ForbiddenHours {
+                start: hm(10, 0),
+                end: hm(19, 0)
+            }
+        );
+
+        assert!(summer.forbids(local_seconds(MONDAY, hm(12, 0))));
+        assert!(!summer.forbids(local_seconds(MONDAY, hm(19, 0))));
+        // 2024-06-01 is out of the validity range
+        assert!(!summer.forbids(local_seconds(MONDAY + 152, hm(12, 0))));
+
+        let night = Restriction::parse("no:79200-3600").unwrap();
+        assert!(night.forbids(local_seconds(MONDAY, hm(0, 30))));
+        assert!(!night.forbids(local_seconds(MONDAY, hm(1, 0))));
+    }
+
+    fn summer_and_winter() -> Vec<Season> {
+        vec![
+            Season {
+                name: "summer".to_string(),
+                valid: (1101, 331),
+                slots: vec![
+                    Some(vec![Slot::new(hm(21, 0), 3600)]),
+                    None,
+                    Some(Vec::new()),
+                ],
+                program_start: Some(hm(21, 0)),
+            },
+            Season {
+                name: "winter".to_string(),
+                valid: (601, 831),
+                slots: vec![Some(vec![Slot::new(hm(10, 0), 1800)]), None, None],
+                program_start: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn season_replaces_slots_in_its_date_range() {
+        let zones = program_zones();
+        let controller = ControllerSchedule {
+            seasons: summer_and_winter(),
+            ..independent()
+        };
+
+        // 2024-01-01, summer: zone 0 moves to the evening, zone 2 is off
+        let summer_day = 19723;
+        assert!(active(&controller, &zones, summer_day, hm(5, 30)).is_empty());
+        assert_eq!(active(&controller, &zones, summer_day, hm(6, 30)), vec![1]);
+        assert!(active(&controller, &zones, summer_day, hm(7, 10)).is_empty());
+        assert_eq!(active(&controller, &zones, summer_day, hm(21, 30)), vec![0]);
+
+        // 2024-07-01, winter
+        let winter_day = 19905;
+        assert_eq!(controller.season_at(winter_day).unwrap().name, "winter");
+        assert_eq!(active(&controller, &zones, winter_day, hm(10, 15)), vec![0]);
+        assert_eq!(active(&controller, &zones, winter_day, hm(7, 10)), vec![2]);
+
+        // 2024-04-15, no season: the zones' own slots
+        let spring_day = 19828;
+        assert_eq!(controller.season_at(spring_day), None);
+        assert_eq!(active(&controller, &zones, spring_day, hm(5, 30)), vec![0]);
+    }
+
+    #[test]
+    fn season_moves_the_program_start() {
+        let zones = program_zones();
+        let controller = ControllerSchedule {
+            seasons: summer_and_winter(),
+            ..program(hm(5, 0), vec![0, 1, 2])
+        };
+
+        // 2024-01-01: zone 0 for an hour from 21:00, then zone 1. Zone 2 has no slots.
+        let summer_day = 19723;
+        assert_eq!(active(&controller, &zones, summer_day, hm(21, 30)), vec![0]);
+        assert_eq!(active(&controller, &zones, summer_day, hm(22, 30)), vec![1]);
+        assert!(active(&controller, &zones, summer_day, hm(23, 0)).is_empty());
+        assert!(active(&controller, &zones, summer_day, hm(5, 30)).is_empty());
+    }
+
+    #[test]
+    fn seasons_round_trip() {
+        let names = ["costado", "toberas", "rotor"];
+        for season in summer_and_winter() {
+            let encoded = season.encode(&names);
+            assert_eq!(Season::parse(&encoded, &names), Some(season));
+        }
+        assert_eq!(
+            summer_and_winter()[0].encode(&names),
+            "summer@1101-0331;program=75600;costado=75600:3600;rotor="
+        );
+
+        assert_eq!(Season::parse("summer@1101-0331;patio=0:60", &names), None);
+        assert_eq!(Season::parse("summer@1301-0331", &names), None);
+        assert_eq!(Season::parse("sum mer@1101-0331", &names), None);
+        assert_eq!(Season::parse("summer", &names), None);
+    }
+
+    #[test]
+    fn full_season_fits_its_nvs_buffer() {
+        let names = [
+            "costado_180",
+            "toberas_frente",
+            "rotor_fondo",
+            "goteo_canteros",
+        ];
+        let slots = vec![Slot::solar(Anchor::Sunrise(-43200), 86400); MAX_SLOTS];
+        let season = Season {
+            name: "a".repeat(MAX_SEASON_NAME),
+            valid: (1101, 331),
+            slots: vec![Some(slots); names.len()],
+            program_start: Some(SECONDS_PER_DAY - 1),
+        };
+        assert!(season.encode(&names).len() <= MAX_SEASON_LEN);
+    }
+
+    #[test]
+    fn restrictions_round_trip() {
+        let encoded = "odd,no:36000-68400@1201-0229";
+        let restrictions = parse_restrictions(encoded).unwrap();
+        assert_eq!(restrictions.len(), 2);
+        assert_eq!(encode_restrictions(&restrictions), encoded);
+        assert_eq!(parse_restrictions(""), Some(Vec::new()));
+
+        assert_eq!(parse_restrictions("odd,"), None);
+        assert_eq!(parse_restrictions("no:1-2@1301-0101"), None);
+        assert_eq!(parse_restrictions("no:86400-1"), None);
+        assert_eq!(parse_restrictions(&["odd"; 7].join(",")), None);
+    }
+
+    #[test]
+    fn controller_reports_blocking_restriction() {
+        let controller = ControllerSchedule {
+            restrictions: parse_restrictions("even,no:36000-68400").unwrap(),
+            ..independent()
+        };
+
+        let blocked = controller.restriction_at(local_seconds(MONDAY, hm(6, 0)));
+        assert_eq!(blocked.map(|r| r.rule), Some(Rule::EvenDays));
+        let blocked = controller.restriction_at(local_seconds(MONDAY + 1, hm(12, 0)));
+        assert!(matches!(
+            blocked.map(|r| r.rule),
+            Some(Rule::ForbiddenHours { .. })
+        ));
+        assert!(controller
+            .restriction_at(local_seconds(MONDAY + 1, hm(6, 0)))
+            .is_none());
+    }
+
+    #[test]
+    fn override_holds_until_it_expires() {
+        let now = local_seconds(MONDAY, hm(6, 0));
+        let off = Override {
+            on: false,
+            until: Some(now + 3600),
+        };
+        assert!(!off.apply(true, now));
+        assert!(off.apply(true, now + 3600));
+        assert!(!off.apply(false, now + 3600));
+
+        let on = Override {
+            on: true,
+            until: None,
+        };
+        assert!(on.apply(false, now + 100 * 86400));
+    }
+
+    #[test]
+    fn master_leads_and_lags_the_zones() {
+        let timing = MasterTiming { lead: 5, lag: 30 };
+
+        // Idle
+        assert!(!timing.should_be_on(false, None, None, None));
+        assert!(!timing.should_be_on(false, Some(3600), Some(600), None));
+
+        // Lead: on 5s before a run, zones wait until it has been on that long
+        assert!(timing.should_be_on(false, Some(5), None, None));
+        assert!(!timing.may_open(None));
+        assert!(!timing.may_open(Some(4)));
+        assert!(timing.may_open(Some(5)));
+
+        // A zone asked to open right away keeps it on through the lead
+        assert!(timing.should_be_on(true, None, None, Some(0)));
+        assert!(timing.should_be_on(false, None, None, Some(4)));
+
+        // Lag after the last zone closed
+        assert!(timing.should_be_on(false, None, Some(29), Some(600)));
+        assert!(!timing.should_be_on(false, None, Some(30), Some(600)));
+    }
+
+    #[test]
+    fn master_without_lead_opens_zones_at_once() {
+        let timing = MasterTiming { lead: 0, lag: 0 };
+        assert!(timing.may_open(Some(0)));
+        assert!(timing.should_be_on(false, Some(0), None, None));
+        assert!(!timing.should_be_on(false, Some(1), Some(0), Some(0)));
+    }
+
+    #[test]
+    fn manual_mode_reverts_after_its_timeout() {
+        let since = local_seconds(MONDAY, hm(10, 0));
+        let deadline = manual_mode_deadline(true, since, 60).unwrap();
+        assert_eq!(deadline, local_seconds(MONDAY, hm(11, 0)));
+
+        let expired = |now| manual_mode_expired(true, since, 60, now);
+        assert_eq!(expired(local_seconds(MONDAY, hm(10, 59))), None);
+        assert_eq!(expired(local_seconds(MONDAY, hm(11, 0))), Some(deadline));
+        // The clock jumping past the deadline reverts right away, jumping back doesn't
+        assert_eq!(expired(local_seconds(MONDAY + 3, hm(8, 0))), Some(deadline));
+        assert_eq!(expired(local_seconds(MONDAY - 1, hm(12, 0))), None);
+        assert_eq!(
+            manual_mode_expired(false, since, 60, local_seconds(MONDAY + 3, 0)),
+            None
+        );
+
+        // Off, never times out, or started before the start time was recorded
+        assert_eq!(manual_mode_deadline(false, since, 60), None);
+        assert_eq!(manual_mode_deadline(true, since, 0), None);
+        assert_eq!(manual_mode_deadline(true, 0, 60), None);
+    }
+
+    #[test]
+    fn manual_run_minutes_are_limited() {
+        assert_eq!(manual_run_duration("0"), Some(Duration::ZERO));
+        assert_eq!(manual_run_duration("10"), Some(Duration::from_secs(600)));
+        assert_eq!(
+            manual_run_duration("720"),
+            Some(Duration::from_secs(12 * 3600))
+        );
+        assert_eq!(manual_run_duration("721"), None);
+        assert_eq!(manual_run_duration("-1"), None);
+        assert_eq!(manual_run_duration("ten"), None);
+    }
+
+    #[test]
+    fn manual_run_ends_after_its_duration() {
+        let start = Instant::now();
+        let mut run = ManualRun::default();
+        assert_eq!(run.remaining(start), None);
+
+        run.start(start, Duration::from_secs(600));
+        assert_eq!(run.remaining(start), Some(Duration::from_secs(600)));
+        assert!(!run.finish(start + Duration::from_secs(599)));
+        assert!(run.finish(start + Duration::from_secs(600)));
+        assert_eq!(run, ManualRun::default());
+        // Only reported once
+        assert!(!run.finish(start + Duration::from_secs(601)));
+    }
+
+    #[test]
+    fn new_manual_run_replaces_the_one_going() {
+        let start = Instant::now();
+        let mut run = ManualRun::default();
+        run.start(start, Duration::from_secs(3600));
+
+        let later = start + Duration::from_secs(300);
+        run.start(later, Duration::from_secs(60));
+        assert_eq!(run.remaining(later), Some(Duration::from_secs(60)));
+        assert!(run.finish(later + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn toggling_cancels_the_manual_run() {
+        let start = Instant::now();
+        let mut run = ManualRun::default();
+        run.start(start, Duration::from_secs(600));
+
+        run.cancel();
+        assert_eq!(run.remaining(start), None);
+        // Cancelled, not finished: nothing to report when its time would have been up
+        assert!(!run.finish(start + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn safety_cutoff_closes_a_valve_left_on() {
+        let mut cutoff = SafetyCutoff::new(3600);
+        assert!(cutoff.allow(true, None));
+        assert!(cutoff.allow(true, Some(3599)));
+        assert!(!cutoff.allow(true, Some(3600)));
+        assert!(cutoff.tripped);
+
+        // Still asked for but closed: stays off, the on-time starts over if it were reopened
+        assert!(!cutoff.allow(true, None));
+        assert!(!cutoff.allow(true, Some(0)));
+    }
+
+    #[test]
+    fn safety_cutoff_rearms_on_a_new_request() {
+        let mut cutoff = SafetyCutoff::new(60);
+        assert!(!cutoff.allow(true, Some(60)));
+
+        // Still wanted, e.g. by a toggle left on, until a new request comes in
+        cutoff.rearm();
+        assert!(cutoff.allow(true, None));
+        assert!(!cutoff.allow(true, Some(60)));
+    }
+
+    #[test]
+    fn safety_cutoff_rejects_runs_past_the_limit() {
+        let cutoff = SafetyCutoff::new(3600);
+        assert!(cutoff.covers(3600));
+        assert!(!cutoff.covers(3601));
+
+        // Zones without a limit of their own still can't be set to water all day
+        let cutoff = SafetyCutoff::new(DEFAULT_MAX_ON);
+        assert!(cutoff.covers(5 * 3600));
+        assert!(!cutoff.covers(u64::from(SECONDS_PER_DAY)));
+    }
+
+    #[test]
+    fn safety_cutoff_resets_once_nothing_wants_the_valve() {
+        let mut cutoff = SafetyCutoff::new(60);
+        assert!(!cutoff.allow(true, Some(60)));
+        assert!(!cutoff.allow(false, None));
+        assert!(!cutoff.tripped);
+
+        // The next run opens it again
+        assert!(cutoff.allow(true, None));
+    }
+
+    #[test]
+    fn disabled_zone_keeps_slots_but_never_runs() {
+        let mut zones = program_zones();
+        zones[0].enabled = false;
+
+        assert!(active(&independent(), &zones, MONDAY, hm(5, 10)).is_empty());
+        assert_eq!(zones[0].next_run_day(MONDAY), None);
+        assert_eq!(zones[0].slots.len(), 1);
+
+        // The program moves the next zone up
+        let controller = program(hm(5, 0), vec![0, 1]);
+        assert_eq!(active(&controller, &zones, MONDAY, hm(5, 10)), vec![1]);
+    }
+
+    #[test]
+    fn catch_up_finds_runs_inside_the_gap() {
+        let zones = program_zones();
+        let planned = independent().scheduled_runs(MONDAY, &zones);
+        // Off from 6:00 to 8:00: costado resumes, toberas and rotor were missed
+        let (last_seen, now) = (
+            local_seconds(MONDAY, hm(6, 0)),
+            local_seconds(MONDAY, hm(8, 0)),
+        );
+
+        let caught_up = catch_up_runs(&planned, last_seen, now, 3 * 3600);
+        assert_eq!(
+            caught_up
+                .iter()
+                .map(|run| (run.zone, run.start))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, local_seconds(MONDAY, hm(8, 0))),
+                (2, local_seconds(MONDAY, hm(8, 45))),
+            ]
+        );
+        assert_eq!(caught_up[1].duration, 40 * 60);
+
+        // Toberas was due 1h45m ago, out of a 1h30m grace period
+        let caught_up = catch_up_runs(&planned, last_seen, now, 90 * 60);
+        assert_eq!(caught_up.len(), 1);
+        assert_eq!(caught_up[0].zone, 2);
+
+        assert!(catch_up_runs(&planned, last_seen, now, 0).is_empty());
+    }
+
+    #[test]
+    fn catch_up_keeps_soak_between_cycles() {
+        let zones = vec![soaked(hm(5, 0), 40 * 60)];
+        let planned = independent().scheduled_runs(MONDAY, &zones);
+        let now = local_seconds(MONDAY, hm(7, 0));
+
+        let caught_up = catch_up_runs(&planned, local_seconds(MONDAY, hm(4, 0)), now, 3 * 3600);
+        assert_eq!(caught_up.len(), 2);
+        assert_eq!(caught_up[0].start, now);
+        assert_eq!(caught_up[1].start, now + 20 * 60 + 30 * 60);
+    }
+
+    #[test]
+    fn catch_up_runs_are_scheduled() {
+        let zones = program_zones();
+        let now = local_seconds(MONDAY, hm(8, 0));
+        let controller = ControllerSchedule {
+            catch_up: vec![Run::new(1, now, 45 * 60)],
+            ..independent()
+        };
+
+        assert_eq!(active(&controller, &zones, MONDAY, hm(8, 10)), vec![1]);
+        assert!(active(&controller, &zones, MONDAY, hm(8, 45)).is_empty());
+    }
+
+    #[test]
+    fn upcoming_lists_the_next_runs_across_days() {
+        let zones = program_zones();
+        let now = local_seconds(MONDAY, hm(6, 30));
+
+        let upcoming = independent().upcoming(now, &zones, 5);
+        assert_eq!(
+            upcoming
+                .iter()
+                .map(|(run, _)| (run.zone, run.start))
+                .collect::<Vec<_>>(),
+            vec![
+                // Still running
+                (1, local_seconds(MONDAY, hm(6, 15))),
+                (2, local_seconds(MONDAY, hm(7, 0))),
+                (0, local_seconds(MONDAY + 1, hm(5, 0))),
+                (1, local_seconds(MONDAY + 1, hm(6, 15))),
+                (2, local_seconds(MONDAY + 1, hm(7, 0))),
+            ]
+        );
+        assert!(upcoming.iter().all(|(_, reason)| *reason == Reason::Slot));
+
+        // Nothing enabled, nothing planned
+        let disabled: Vec<ZoneSchedule> = zones
+            .into_iter()
+            .map(|zone| ZoneSchedule {
+                enabled: false,
+                ..zone
+            })
+            .collect();
+        assert!(independent().upcoming(now, &disabled, 5).is_empty());
+    }
+
+    #[test]
+    fn clock_is_not_trusted_until_synced() {
+        assert_eq!(ClockTrust::at_boot(None), ClockTrust::NeverSynced);
+        assert_eq!(ClockTrust::at_boot(Some(0)), ClockTrust::NeverSynced);
+        // Saved every minute from the uptime of a controller that was never synced
+        assert_eq!(ClockTrust::at_boot(Some(3 * 3600)), ClockTrust::NeverSynced);
+        assert_eq!(
+            ClockTrust::at_boot(Some(1_704_085_200)),
+            ClockTrust::Restored
+        );
+
+        assert!(!ClockTrust::NeverSynced.is_trusted());
+        assert!(!ClockTrust::Restored.is_trusted());
+        assert!(ClockTrust::Synced.is_trusted());
+    }
+
+    #[test]
+    fn upcoming_tells_program_and_catch_up_runs_apart() {
+        let zones = program_zones();
+        let now = local_seconds(MONDAY, hm(8, 0));
+        let controller = ControllerSchedule {
+            catch_up: vec![Run::new(1, now + 60, 45 * 60)],
+            ..program(hm(22, 0), vec![0])
+        };
+
+        let upcoming = controller.upcoming(now, &zones, 2);
+        assert_eq!(upcoming[0].0.zone, 1);
+        assert_eq!(upcoming[0].1, Reason::CatchUp);
+        assert_eq!(upcoming[1].0.start, local_seconds(MONDAY, hm(22, 0)));
+        assert_eq!(upcoming[1].1, Reason::Program);
+    }
+
+    #[test]
+    fn zone_gap_delays_the_next_zone() {
+        let zones = program_zones();
+        let controller = ControllerSchedule {
+            zone_gap: 30,
+            ..independent()
+        };
+        let runs = controller.runs(MONDAY, &zones);
+
+        // Toberas was meant to open as costado closes
+        let toberas = run_of(&runs, 1, MONDAY);
+        assert_eq!(toberas.requested_start, local_seconds(MONDAY, hm(6, 15)));
+        assert_eq!(toberas.start, local_seconds(MONDAY, hm(6, 15)) + 30);
+        assert_eq!(toberas.duration, 45 * 60);
+        assert!(toberas.is_queued(local_seconds(MONDAY, hm(6, 15)) + 10));
+
+        // Rotor opens while toberas is still on, nothing closes right before it
+        assert_eq!(
+            run_of(&runs, 2, MONDAY).start,
+            local_seconds(MONDAY, hm(7, 0))
+        );
+    }
+
+    #[test]
+    fn zone_gap_with_one_valve_at_a_time() {
+        let zones = overlapping_zones();
+        let controller = ControllerSchedule {
+            max_concurrent: 1,
+            zone_gap: 60,
+            ..independent()
+        };
+        let runs = controller.runs(MONDAY, &zones);
+        let mut monday: Vec<Run> = runs
+            .into_iter()
+            .filter(|run| split_local_timestamp(run.requested_start).0 == MONDAY)
+            .collect();
+        monday.sort_by_key(|run| run.start);
+
+        for pair in monday.windows(2) {
+            assert_eq!(pair[1].start, pair[0].end() + 60);
+        }
+    }
+
+    #[test]
+    fn program_leaves_the_zone_gap() {
+        let zones = program_zones();
+        let controller = ControllerSchedule {
+            zone_gap: 60,
+            ..program(hm(5, 0), vec![0, 1])
+        };
+
+        assert_eq!(
+            controller
+                .runs(MONDAY, &zones)
+                .into_iter()
+                .filter(|run| split_local_timestamp(run.start).0 == MONDAY)
+                .map(|run| run.start)
+                .collect::<Vec<_>>(),
+            vec![
+                local_seconds(MONDAY, hm(5, 0)),
+                local_seconds(MONDAY, hm(6, 16)),
+            ]
+        );
+    }
+
+    #[test]
+    fn queue_carries_over_midnight() {
+        let zones = vec![
+            ZoneSchedule::new(vec![Slot::new(hm(23, 0), 2 * 3600)]),
+            ZoneSchedule::new(vec![Slot::new(hm(23, 30), 3600)]),
+        ];
+        let controller = ControllerSchedule {
+            max_concurrent: 1,
+            ..independent()
+        };
+
+        assert_eq!(active(&controller, &zones, MONDAY + 1, hm(0, 30)), vec![0]);
+        assert_eq!(active(&controller, &zones, MONDAY + 1, hm(1, 30)), vec![1]);
+        assert!(active(&controller, &zones, MONDAY + 1, hm(2, 0)).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+//! Offline sunrise/sunset calculator (NOAA sunrise equation), good to a couple of minutes.
+
+use std::f64::consts::PI;
+
+/// Sunrise and sunset of one day, in local seconds from midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SunTimes {
+    pub sunrise: u32,
+    pub sunset: u32,
+}
+
+/// Sunrise and sunset for `day` (local day number) at the given position in degrees
+/// (north and east positive). `None` during polar day or night.
+pub fn sun_times(day: i64, latitude: f64, longitude: f64, utc_offset: i32) -> Option<SunTimes> {
+    // Days since J2000.0 (2000-01-01 12:00 UTC is day 10957 at noon)
+    let n = (day - 10957) as f64;
+    let mean_solar_noon = n - longitude / 360.0;
+
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit =
+        mean_solar_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * 23.4397f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    // -0.833° accounts for refraction and the size of the sun's disc
+    let cos_hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let half_day = cos_hour_angle.acos() / (2.0 * PI);
+
+    // `transit` is in days from J2000.0 noon UTC, i.e. 12:00 UTC of day 10957 + transit
+    let to_local = |days: f64| -> u32 {
+        let utc = ((days + 0.5) * 86400.0).round() as i64 + 10957 * 86400;
+        (utc + i64::from(utc_offset)).rem_euclid(86400) as u32
+    };
+
+    Some(SunTimes {
+        sunrise: to_local(transit - half_day),
+        sunset: to_local(transit + half_day),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUENOS_AIRES: (f64, f64) = (-34.6037, -58.3816);
+    const UTC_MINUS_3: i32 = -3 * 3600;
+
+    fn assert_close(actual: u32, hours: u32, minutes: u32) {
+        let expected = hours * 3600 + minutes * 60;
+        assert!(
+            actual.abs_diff(expected) <= 3 * 60,
+            "{}:{:02} is not close to {}:{:02}",
+            actual / 3600,
+            actual % 3600 / 60,
+            hours,
+            minutes
+        );
+    }
+
+    #[test]
+    fn buenos_aires_winter_and_summer() {
+        let (latitude, longitude) = BUENOS_AIRES;
+
+        // 2024-06-21
+        let winter = sun_times(19895, latitude, longitude, UTC_MINUS_3).unwrap();
+        assert_close(winter.sunrise, 8, 0);
+        assert_close(winter.sunset, 17, 50);
+
+        // 2024-12-21
+        let summer = sun_times(20078, latitude, longitude, UTC_MINUS_3).unwrap();
+        assert_close(summer.sunrise, 5, 37);
+        assert_close(summer.sunset, 20, 6);
+    }
+
+    #[test]
+    fn polar_day_has_no_sunrise() {
+        // 2024-06-21 near Svalbard
+        assert_eq!(sun_times(19895, 78.2, 15.6, 3600), None);
+    }
+}